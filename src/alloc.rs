@@ -0,0 +1,210 @@
+//! Memory allocation failures.
+//!
+//! [`FailpointAllocator`] wraps a global allocator and makes
+//! allocations failpoints.  When one is triggered the allocation
+//! returns null, so fallible allocation paths such as
+//! [`Vec::try_reserve()`] can be tested with
+//! [`test_codepath!`](crate::test_codepath!).
+//!
+//! Install it as the global allocator of a test binary:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! use failpoint::alloc::FailpointAllocator;
+//!
+//! #[global_allocator]
+//! static ALLOC: FailpointAllocator<System> = FailpointAllocator::new(System);
+//! ```
+//!
+//! Almost everything allocates, and an infallible allocation that
+//! fails aborts the process.  So allocations are only failpoints on a
+//! thread while an [`AllocFailGuard`] is alive, which should be
+//! created around the code whose allocations may fail, and a size
+//! range can be set to ignore small allocations.
+//!
+//! ```rust
+//! use std::alloc::System;
+//! use std::collections::TryReserveError;
+//!
+//! use failpoint::alloc::{AllocFailGuard, FailpointAllocator};
+//! use failpoint::test_codepath;
+//!
+//! #[global_allocator]
+//! static ALLOC: FailpointAllocator<System> = FailpointAllocator::new(System).with_min_size(4096);
+//!
+//! fn make_buffer(len: usize) -> Result<Vec<u8>, TryReserveError> {
+//!     let mut buf = Vec::new();
+//!     buf.try_reserve_exact(len)?;
+//!     buf.resize(len, 0);
+//!     Ok(buf)
+//! }
+//!
+//! let res = test_codepath! {
+//!     codepath {
+//!         {
+//!             let _fail = AllocFailGuard::new();
+//!             make_buffer(1 << 20).map(|_| ())
+//!         }
+//!     }
+//! };
+//!
+//! assert!(res.success());
+//! assert_eq!(res.expected_trigger_count, 1);
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout};
+#[cfg(feature = "failpoint_enabled")]
+use std::cell::Cell;
+
+#[cfg(feature = "failpoint_enabled")]
+use crate::failpoint_state::holds_state_lock;
+#[cfg(feature = "failpoint_enabled")]
+use crate::io::location;
+#[cfg(feature = "failpoint_enabled")]
+use crate::lock_state;
+
+#[cfg(feature = "failpoint_enabled")]
+thread_local! {
+    // True while allocations on this thread are failpoints.  Const
+    // initialized so that reading it doesn't allocate.
+    static FAIL_ALLOCS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A global allocator whose allocations are failpoints.
+///
+/// `alloc()`, `alloc_zeroed()` and `realloc()` are failpoints
+/// described as `FailpointAllocator::alloc`,
+/// `FailpointAllocator::alloc_zeroed` and `FailpointAllocator::realloc`,
+/// but only on threads with a live [`AllocFailGuard`], and only for
+/// sizes within the allocator's size range.  Deallocations are never
+/// failpoints.
+///
+/// Allocations made while reaching a failpoint, for example to log
+/// it, are passed straight through.
+#[derive(Debug)]
+pub struct FailpointAllocator<A> {
+    inner: A,
+    min_size: usize,
+    max_size: usize,
+}
+
+impl<A> FailpointAllocator<A> {
+    /// Wraps the allocator `inner`.  Allocations of any size are
+    /// failpoints.
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            min_size: 0,
+            max_size: usize::MAX,
+        }
+    }
+
+    /// Only makes allocations of at least `size` bytes failpoints.
+    pub const fn with_min_size(mut self, size: usize) -> Self {
+        self.min_size = size;
+        self
+    }
+
+    /// Only makes allocations of at most `size` bytes failpoints.
+    pub const fn with_max_size(mut self, size: usize) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    pub fn get_ref(&self) -> &A {
+        &self.inner
+    }
+
+    // Returns true if the allocation of `size` bytes should fail.
+    #[cfg(feature = "failpoint_enabled")]
+    fn should_fail(&self, desc: &'static str, size: usize) -> bool {
+        if size < self.min_size || size > self.max_size {
+            return false;
+        }
+        if !FAIL_ALLOCS.try_with(|f| f.get()).unwrap_or(false) || holds_state_lock() {
+            return false;
+        }
+
+        let loc = location!(desc);
+        let mut g = lock_state();
+        if !g.active || !g.should_trigger(&loc) {
+            return false;
+        }
+        g.report_fault(&loc, &format!("a failed allocation of {size} bytes"));
+        true
+    }
+
+    #[cfg(not(feature = "failpoint_enabled"))]
+    #[inline]
+    fn should_fail(&self, _desc: &'static str, _size: usize) -> bool {
+        false
+    }
+}
+
+// SAFETY: Either returns null, which any allocation may do, or
+// forwards to `inner`.
+unsafe impl<A: GlobalAlloc> GlobalAlloc for FailpointAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.should_fail("FailpointAllocator::alloc", layout.size()) {
+            return std::ptr::null_mut();
+        }
+        unsafe { self.inner.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if self.should_fail("FailpointAllocator::alloc_zeroed", layout.size()) {
+            return std::ptr::null_mut();
+        }
+        unsafe { self.inner.alloc_zeroed(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.inner.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.should_fail("FailpointAllocator::realloc", new_size) {
+            return std::ptr::null_mut();
+        }
+        unsafe { self.inner.realloc(ptr, layout, new_size) }
+    }
+}
+
+/// Makes allocations on this thread failpoints while it is alive.
+/// See [`FailpointAllocator`].
+///
+/// Guards may be nested.  Dropping one restores the setting from when
+/// it was created.
+pub struct AllocFailGuard {
+    #[cfg(feature = "failpoint_enabled")]
+    was_enabled: bool,
+}
+
+impl AllocFailGuard {
+    #[cfg(feature = "failpoint_enabled")]
+    pub fn new() -> Self {
+        Self {
+            was_enabled: FAIL_ALLOCS.with(|f| f.replace(true)),
+        }
+    }
+
+    #[cfg(not(feature = "failpoint_enabled"))]
+    #[inline]
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for AllocFailGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for AllocFailGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "failpoint_enabled")]
+        FAIL_ALLOCS.with(|f| f.set(self.was_enabled));
+    }
+}
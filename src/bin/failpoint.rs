@@ -0,0 +1,112 @@
+//! Command line tool for driving the failpoints in a running process.
+//!
+//! The process must have started a control server, see
+//! `failpoint::control`.  Build with the `cli` feature:
+//!
+//! ```shell
+//! cargo install failpoint --features cli
+//! failpoint --socket /tmp/my_service.failpoint list
+//! ```
+use std::env;
+use std::io;
+use std::process::ExitCode;
+
+use failpoint::control::ControlClient;
+
+const USAGE: &str = "\
+Usage: failpoint [--socket PATH] COMMAND [ARGS]
+
+Drives the failpoints in a process running a failpoint control server.
+The socket can also be given with the FAILPOINT_SOCKET environment
+variable.
+
+Commands:
+  status               Show the active flag, mode, counter and trigger
+  counter              Print the current failpoint counter
+  list                 List the counted failpoints
+  count                Enter count mode and reset the counter
+  trigger N            Trigger the Nth failpoint
+  trigger-named DESC   Trigger the first failpoint described by DESC
+  on                   Activate failpoints
+  off                  Deactivate failpoints
+  verbosity LEVEL      Set the verbosity to none, moderate or extreme
+  tail                 Print log messages until interrupted
+";
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1).peekable();
+    let mut socket = env::var("FAILPOINT_SOCKET").ok();
+
+    while let Some(arg) = args.peek() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            "-s" | "--socket" => {
+                args.next();
+                socket = args.next();
+            }
+            _ => break,
+        }
+    }
+
+    let Some(socket) = socket else {
+        eprint!("failpoint: no socket given\n\n{USAGE}");
+        return ExitCode::from(2);
+    };
+    let Some(cmd) = args.next() else {
+        eprint!("failpoint: no command given\n\n{USAGE}");
+        return ExitCode::from(2);
+    };
+    let rest: Vec<String> = args.collect();
+
+    match run(&socket, &cmd, &rest.join(" ")) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("failpoint: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(socket: &str, cmd: &str, args: &str) -> io::Result<()> {
+    let mut client = ControlClient::connect(socket)?;
+
+    match cmd {
+        "counter" => {
+            let status = client.command("status")?;
+            let count = status
+                .iter()
+                .find_map(|l| l.strip_prefix("count "))
+                .ok_or_else(|| io::Error::other("no counter in status"))?;
+            println!("{count}");
+        }
+        "list" => {
+            for line in client.command("list")? {
+                match line.split_once(' ') {
+                    Some((n, loc)) => println!("{n:>4}| {loc}"),
+                    None => println!("{line}"),
+                }
+            }
+        }
+        "tail" => {
+            client.tail(|msg| {
+                println!("{msg}");
+                true
+            })?;
+        }
+        _ => {
+            let command = if args.is_empty() {
+                cmd.to_string()
+            } else {
+                format!("{cmd} {args}")
+            };
+            for line in client.command(&command)? {
+                println!("{line}");
+            }
+        }
+    }
+
+    Ok(())
+}
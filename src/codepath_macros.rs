@@ -5,12 +5,57 @@
 /// failpoint and verify error handling. Setup and cleanup blocks can be provided
 /// to reset state between iterations.
 ///
+/// If a [`crashpoint!`](crate::crashpoint!) is triggered the code path
+/// is abandoned and the `recover` block is run, before the `after`
+/// block.  The crash counts as the code path failing.
+///
+/// The `codepath` expression is run in a closure, so that crashes can
+/// be caught, whether or not failpoints are enabled.  So `?` and
+/// `return` in it return from the closure, making the code path's
+/// result, rather than from the enclosing function, and
+/// `break` and `continue` can't refer to a loop around the macro.
+/// Values borrowed by the code path are captured by the closure.
+///
+/// Error handling code often has failpoints of its own, which are
+/// never counted because counting runs the code path without errors.
+/// With `depth N`, after an error is injected the failpoints reached
+/// afterwards are found, and the code path is run again injecting an
+/// error at each of them as well, up to `N` errors in a run.  The
+/// expected trigger count includes these runs, and
+/// [`CodePathResult::failure_sequences`](crate::CodePathResult::failure_sequences)
+/// lists the ordinals and locations of the errors injected in each
+/// run that wasn't explored further.
+///
+/// With `limits`, a [`Limits`](crate::Limits) can cap the number of
+/// runs and the time taken, or trigger a random sample of the counted
+/// failpoints.
+/// [`CodePathResult::complete`](crate::CodePathResult::complete) is
+/// false if any runs were skipped, because of the limits or because a
+/// run that failed the test stopped the exploration, and
+/// [`CodePathResult::skipped`](crate::CodePathResult::skipped) lists
+/// them.
+///
+/// With `threads N`, the trigger iterations are run in parallel on `N`
+/// threads, each in its own [`Session`](crate::Session), and their
+/// results are merged.  The `before`, `codepath`, `recover` and
+/// `after` blocks are then run on several threads at once, so they
+/// must not share anything that isn't thread safe, and each must set
+/// up and clean up its own resources, for example by using a
+/// different temporary directory.  Failpoints reached by threads the
+/// code path spawns are not part of the iteration's session.
+///
 /// # Syntax
 ///
 /// ```ignore
-/// test_codepath!({ setup }; code_path; { cleanup })
-/// test_codepath!(code_path; { cleanup })
-/// test_codepath!(code_path)
+/// test_codepath!{
+///     depth max_errors;             // optional, defaults to 1
+///     limits limits;                // optional, see `Limits`
+///     threads n;                    // optional, runs in parallel
+///     before { setup };             // optional
+///     codepath { code_path };
+///     recover { crash_recovery };   // optional
+///     after { cleanup }             // optional
+/// }
 /// ```
 ///
 /// # Returns
@@ -58,22 +103,147 @@ macro_rules! test_codepath {
 	}
     };
 
-    { before $before: block ; codepath $codepath: expr ; after $after: block } => {
+    (@depth) => { 1 };
+
+    (@depth $depth: expr) => { $depth };
+
+    (@limits) => { failpoint::Limits::new() };
+
+    (@limits $limits: expr) => { $limits };
+
+    { $(depth $depth: expr ;)? $(limits $limits: expr ;)? $(threads $threads: expr ;)? $(before $before: block ;)? codepath $codepath: expr $(; recover $recover: block)? $(; after $after: block)? $(;)? } => {
+	test_codepath!{ @run depth test_codepath!(@depth $($depth)?); limits test_codepath!(@limits $($limits)?); threads ($($threads)?); before { $($before)? }; codepath $codepath; recover { $($recover)? }; after { $($after)? } }
+    };
+
+    { @run depth $depth: expr ; limits $limits: expr ; threads ($threads: expr) ; before $before: block ; codepath $codepath: expr ; recover $recover: block ; after $after: block } => {
 	{
-	    use failpoint::{start_counter, start_trigger, Mode, get_count, CodePathResult,
-			    Verbosity, set_active, ActiveGuard};
+	    use failpoint::{start_counter, start_trigger_sequence, Verbosity, ActiveGuard,
+			    enter_iteration_span, log_event, FailpointEvent, catch_crash,
+			    get_count, get_reached_count, get_injected_count, get_injected_locs,
+			    get_divergence, Iteration, run_iterations_parallel};
+	    let iteration_no = ::std::sync::atomic::AtomicI64::new(0);
+
+	    run_iterations_parallel($threads, $depth, &$limits, |sequence: &[i64]| {
+		let iteration = iteration_no.fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+		// The last error to inject, or 0 in count mode.
+		let trigger_count = sequence.last().copied().unwrap_or(0);
+
+		let _span = enter_iteration_span(stringify!($codepath), iteration, trigger_count);
+
+		test_codepath!(@log Verbosity::Extreme, "\n------------------------------------------------------------".to_string());
+		log_event(FailpointEvent::IterationStart { iteration, trigger: trigger_count });
+
+		test_codepath!(@log Verbosity::Extreme, "Running before block".to_string());
+		{
+		    let act_gaurd_ = ActiveGuard::new(false);
+
+		    {
+			$before;
+		    }
+
+		    drop(act_gaurd_);
+		}
+
+		if sequence.is_empty() {
+		    start_counter();
+		    test_codepath!(@log Verbosity::Extreme, "Running codepath in COUNT mode".to_string());
+		} else {
+		    start_trigger_sequence(sequence);
+		    test_codepath!(@log Verbosity::Extreme, format!("Running codepath in TRIGGER mode, will trigger errors {:?}", sequence));
+		}
+
+		let unexpected = match catch_crash(|| $codepath) {
+		    Ok(res) => {
+			if sequence.is_empty() && res.is_err() {
+			    test_codepath!(@log Verbosity::None,
+					   "Error returned by codepath in count mode. Expected codepath to succeed.".to_string());
+			}
+			if sequence.is_empty() == res.is_err() { Some(res) } else { None }
+		    }
+		    Err(crash_) => {
+			test_codepath!(@log Verbosity::Moderate,
+				       format!("Codepath crashed at {}, running recover block", crash_.loc.format()));
+			let act_gaurd_ = ActiveGuard::new(false);
+
+			{
+			    $recover;
+			}
+
+			drop(act_gaurd_);
+			None
+		    }
+		};
+
+		let it = Iteration {
+		    unexpected,
+		    count: get_count(),
+		    reached: get_reached_count(),
+		    injected: get_injected_count(),
+		    divergence: if sequence.is_empty() { None } else { get_divergence() },
+		    injected_locs: get_injected_locs(),
+		};
+
+		test_codepath!(@log Verbosity::Moderate, "Running after block");
+
+		{
+		    let act_gaurd_ = ActiveGuard::new(false);
+
+		    {
+			$after;
+		    }
+
+		    drop(act_gaurd_);
+		}
+
+		it
+	    })
+	}
+    };
+
+    { @run depth $depth: expr ; limits $limits: expr ; threads () ; before $before: block ; codepath $codepath: expr ; recover $recover: block ; after $after: block } => {
+	{
+	    use failpoint::{start_counter, start_trigger_sequence, Mode, get_count, CodePathResult,
+			    Verbosity, set_active, ActiveGuard, enter_iteration_span,
+			    log_event, FailpointEvent, catch_crash, get_injected_count,
+			    get_reached_count, get_injected_locs, collect_injected_locs,
+			    get_divergence, Location};
+	    let max_depth: usize = $depth;
+	    let limits: failpoint::Limits = $limits;
+	    let start = ::std::time::Instant::now();
+	    let mut skipped: Vec<Vec<i64>> = Vec::new();
 	    let mut mode = Mode::Count;
-	    let mut trigger_count = 0;
+	    let mut sequence: Vec<i64> = Vec::new();
+	    let mut pending: Vec<Vec<i64>> = Vec::new();
+	    let mut failure_sequences: Vec<Vec<(i64, Location)>> = Vec::new();
 	    let mut error_count = i64::MAX;
+	    let mut triggered_count = 0;
+	    let mut iteration = 0;
+	    let mut injected_count = 0;
+	    let mut divergence = None;
+	    let collecting = collect_injected_locs(true);
 
 	    let unexpected_result = loop {
-		if mode == Mode::Trigger && trigger_count > error_count  {
-		    break None;
-		}
+		// The last error to inject, or 0 in count mode.
+		let trigger_count = if mode == Mode::Trigger {
+		    if !pending.is_empty() && limits.exhausted(start, iteration as usize - 1) {
+			test_codepath!(@log Verbosity::Moderate,
+				       format!("Exploration limit reached, skipping {} runs", pending.len()));
+			skipped.append(&mut pending);
+		    }
+		    match pending.pop() {
+			Some(s) => sequence = s,
+			None => break None,
+		    }
+		    sequence[sequence.len() - 1]
+		} else {
+		    0
+		};
+
+		let _span = enter_iteration_span(stringify!($codepath), iteration, trigger_count);
 
 		test_codepath!(@log Verbosity::Extreme, "\n------------------------------------------------------------".to_string());
-		test_codepath!(@log Verbosity::Extreme,
-			       format!("Testing codepath in {} mode", if mode == Mode::Count { "COUNT" } else { "TRIGGER" }));
+		log_event(FailpointEvent::IterationStart { iteration, trigger: trigger_count });
+		iteration += 1;
 
 		test_codepath!(@log Verbosity::Extreme, "Running before block".to_string());
 		{
@@ -90,33 +260,83 @@ macro_rules! test_codepath {
 		    start_counter();
 		    test_codepath!(@log Verbosity::Extreme, "Running codepath in COUNT mode".to_string());
 		} else {
-		    start_trigger(trigger_count);
-		    test_codepath!(@log Verbosity::Extreme, format!("Running codepath in TRIGGER mode, will trigger error {}", trigger_count));
+		    start_trigger_sequence(&sequence);
+		    if sequence.len() == 1 {
+			test_codepath!(@log Verbosity::Extreme, format!("Running codepath in TRIGGER mode, will trigger error {}", trigger_count));
+		    } else {
+			test_codepath!(@log Verbosity::Extreme, format!("Running codepath in TRIGGER mode, will trigger errors {:?}", sequence));
+		    }
 		}
 
-		let res = $codepath;
+		match catch_crash(|| $codepath) {
+		    Ok(res) => {
+			if mode == Mode::Count {
+			    if res.is_err() {
+				test_codepath!(@log Verbosity::None,
+					       "Error returned by codepath in count mode. Expected codepath to succeed.".to_string());
+				break Some(res)
+			    }
+			} else {
+			    if !res.is_err() {
+				test_codepath!(@log Verbosity::None,
+					       format!("Codepath did not fail in trigger mode for errors {:?}.  Expected codepath to fail.",
+						       sequence));
+				break Some(res)
+			    }
+			}
+		    }
+		    Err(crash_) => {
+			// A crash can only happen in trigger mode, and counts as
+			// the codepath failing.
+			test_codepath!(@log Verbosity::Moderate,
+				       format!("Codepath crashed at {}, running recover block", crash_.loc.format()));
+			let act_gaurd_ = ActiveGuard::new(false);
+
+			{
+			    $recover;
+			}
 
-		if mode == Mode::Count {
-		    if res.is_err() {
-			test_codepath!(@log Verbosity::None,
-				       "Error returned by codepath in count mode. Expected codepath to succeed.".to_string());
-			break Some(res)
+			drop(act_gaurd_);
 		    }
-		} else {
-		    if !res.is_err() {
+		}
+
+		if mode == Mode::Trigger {
+		    divergence = get_divergence();
+		    if let Some(ref d) = divergence {
 			test_codepath!(@log Verbosity::None,
-				       format!("Codepath did not fail in trigger mode for error {}.  Expected codepath to fail.",
-					       trigger_count));
-			break Some(res)
+				       format!("Codepath is not deterministic for errors {:?}: {}", sequence, d));
 		    }
 		}
 
 		if mode == Mode::Count {
 		    mode = Mode::Trigger;
-		    trigger_count = 1;
 		    error_count = get_count();
-		} else {
-		    trigger_count += 1;
+		    let (selected, sampled_out) = limits.sample_ordinals(error_count);
+		    skipped.extend(sampled_out.into_iter().map(|n| vec![n]));
+		    pending.extend(selected.into_iter().rev().map(|n| vec![n]));
+		} else if divergence.is_none() {
+		    injected_count += get_injected_count();
+		    triggered_count += 1;
+
+		    // The failpoints reached after the last injected error are
+		    // on the error handling path.  Explore injecting an error
+		    // at each of them too.
+		    let reached = get_reached_count();
+		    if sequence.len() < max_depth && reached > trigger_count {
+			test_codepath!(@log Verbosity::Moderate,
+				       format!("Found {} failpoints after errors {:?}", reached - trigger_count, sequence));
+			error_count += reached - trigger_count;
+			for n in (trigger_count + 1..=reached).rev() {
+			    let mut s = sequence.clone();
+			    s.push(n);
+			    pending.push(s);
+			}
+		    } else {
+			if max_depth > 1 {
+			    test_codepath!(@log Verbosity::Moderate, format!("Failure sequence {:?} done", sequence));
+			}
+			failure_sequences.push(get_injected_locs());
+		    }
 		}
 
 		test_codepath!(@log Verbosity::Moderate, "Running after block");
@@ -130,70 +350,64 @@ macro_rules! test_codepath {
 
 		    drop(act_gaurd_);
 		}
+
+		// A divergence stops the exploration, once the run that
+		// found it has been cleaned up.
+		if divergence.is_some() {
+		    break None;
+		}
 	    };
 
-	    test_codepath!(@log Verbosity::Moderate, format!("Triggered {} of {} errors", trigger_count - 1, error_count));
+	    // A run that found a problem stops the exploration, so the runs
+	    // still to be made are skipped.
+	    let stopped = unexpected_result.is_some() || divergence.is_some();
+	    if !pending.is_empty() {
+		test_codepath!(@log Verbosity::Moderate,
+			       format!("Exploration stopped, skipping {} runs", pending.len()));
+		skipped.append(&mut pending);
+	    }
+
+	    collect_injected_locs(collecting);
+	    log_event(FailpointEvent::Finished { counted: error_count, triggered: triggered_count });
+	    skipped.sort();
 
 	    let ret = CodePathResult{
 		expected_trigger_count: error_count,
-		trigger_count: trigger_count - 1,
+		trigger_count: triggered_count,
+		injected_count,
 		unexpected_result,
+		divergence,
+		failure_sequences,
+		complete: skipped.is_empty() && !stopped,
+		skipped,
 	    };
 
 	    ret
 	}
     };
-
-    { before $before: block ; codepath $codepath: expr ; after $after: block ; } => {
-	test_codepath!{ before $before; codepath $codepath; after $after }
-    };
-
-    { codepath $codepath: expr ; after $after: block } => {
-	test_codepath!{ before {}; codepath $codepath; after $after }
-    };
-
-    { codepath $codepath: expr ; after $after: block ; } => {
-	test_codepath!{ codepath $codepath; after $after }
-    };
-
-    { before $before: block; codepath $codepath: expr } => {
-	test_codepath!{ before $before; codepath $codepath; after {} }
-    };
-
-    { before $before: block; codepath $codepath: expr ; } => {
-	test_codepath!{ before $before; codepath $codepath }
-    };
-
-    { codepath $codepath: expr } => {
-	test_codepath!{ before {}; codepath $codepath; after {} }
-    };
-
-    { codepath $codepath: expr } => {
-	test_codepath!{ codepath $codepath ; }
-    };
-
 }
 
 #[cfg(not(feature = "failpoint_enabled"))]
 #[macro_export]
 macro_rules! test_codepath {
-    { before $before: block ; codepath $codepath: expr ; after $after: block } => {{
+    { $(depth $depth: expr ;)? $(limits $limits: expr ;)? $(threads $threads: expr ;)? $(before $before: block ;)? codepath $codepath: expr $(; recover $recover: block)? $(; after $after: block)? $(;)? } => {{
         use failpoint::CodePathResult;
-        $before;
-        let res = $codepath;
-        $after;
+        $(let _: usize = $depth;)?
+        $(let _: failpoint::Limits = $limits;)?
+        $(let _: usize = $threads;)?
+        $($before;)?
+        let res = (|| $codepath)();
+        $(if false { $recover; })?
+        $($after;)?
         CodePathResult::<_, _> {
             expected_trigger_count: 0,
             trigger_count: 0,
+            injected_count: 0,
             unexpected_result: Some(res),
+            divergence: None,
+            failure_sequences: Vec::new(),
+            complete: true,
+            skipped: Vec::new(),
         }
     }};
-
-    { codepath $codepath: expr ; after $after: block } => {
-        test_codepath!{ before {}; codepath $codepath; after $after }
-    };
-
-    { codepath $codepath: expr } => {
-        test_codepath!{ before {}; codepath $codepath; after {} }
-    };
 }
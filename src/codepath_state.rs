@@ -1,17 +1,402 @@
 use std::fmt::Debug;
+#[cfg(feature = "failpoint_enabled")]
+use std::sync::{Condvar, Mutex};
+#[cfg(feature = "failpoint_enabled")]
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "failpoint_enabled")]
+use std::time::Instant;
 
-use crate::failpoint_state::{get_counted_locs, get_triggered_locs};
-use crate::{Verbosity, log_if_verbose};
+use crate::failpoint_state::{Location, get_counted_locs, get_triggered_locs};
+#[cfg(feature = "failpoint_enabled")]
+use crate::rng::Rng;
+#[cfg(feature = "failpoint_enabled")]
+use crate::{FailpointEvent, Session, collect_injected_locs, log_event};
+use crate::{Verbosity, log_if_verbose, warn_if_verbose};
+
+// See HIDDEN DOC in failpoint_state.rs.
+//
+// Keeps the tracing span for one iteration of `test_codepath!`
+// entered until it is dropped.
+#[doc(hidden)]
+pub struct IterationSpan {
+    #[cfg(feature = "tracing")]
+    _span: tracing::span::EnteredSpan,
+}
+
+// See HIDDEN DOC in failpoint_state.rs.
+//
+// With the `tracing` feature, enters a span named `test_codepath`
+// covering one iteration of `test_codepath!`.  `codepath` is the
+// stringified codepath block and `trigger` is zero in the counting
+// iteration.
+#[doc(hidden)]
+pub fn enter_iteration_span(codepath: &str, iteration: i64, trigger: i64) -> IterationSpan {
+    #[cfg(feature = "tracing")]
+    let codepath = codepath
+        .strip_prefix('{')
+        .and_then(|c| c.strip_suffix('}'))
+        .unwrap_or(codepath)
+        .trim();
+
+    #[cfg(feature = "tracing")]
+    return IterationSpan {
+        _span: tracing::debug_span!(
+            target: "failpoint",
+            "test_codepath",
+            codepath,
+            iteration,
+            trigger
+        )
+        .entered(),
+    };
+
+    #[cfg(not(feature = "tracing"))]
+    {
+        _ = (codepath, iteration, trigger);
+        IterationSpan {}
+    }
+}
+
+/// Limits on how much of a code path [`test_codepath!`](crate::test_codepath!)
+/// explores, for code paths with too many failpoints to trigger them
+/// all.
+///
+/// Runs that are not made because of a limit are listed in
+/// [`CodePathResult::skipped`].
+///
+/// # Examples
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use failpoint::Limits;
+///
+/// let limits = Limits::new()
+///     .sample(20, 42)
+///     .max_iterations(100)
+///     .time_budget(Duration::from_secs(30));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    max_iterations: Option<usize>,
+    time_budget: Option<Duration>,
+    sample: Option<(usize, u64)>,
+}
+
+impl Limits {
+    /// No limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes at most `n` runs that trigger errors.
+    pub fn max_iterations(mut self, n: usize) -> Self {
+        self.max_iterations = Some(n);
+        self
+    }
+
+    /// Makes no more runs once `budget` has passed since the
+    /// exploration started.  A run that is under way is finished.
+    pub fn time_budget(mut self, budget: Duration) -> Self {
+        self.time_budget = Some(budget);
+        self
+    }
+
+    /// Triggers only `k` of the counted failpoints, chosen at random
+    /// from `seed`, so the same seed tests the same failpoints.
+    pub fn sample(mut self, k: usize, seed: u64) -> Self {
+        self.sample = Some((k, seed));
+        self
+    }
+
+    // See HIDDEN DOC in failpoint_state.rs.
+    //
+    // Splits the ordinals `1..=count` into those to trigger and those
+    // skipped by sampling, both in ascending order.
+    #[cfg(feature = "failpoint_enabled")]
+    #[doc(hidden)]
+    pub fn sample_ordinals(&self, count: i64) -> (Vec<i64>, Vec<i64>) {
+        let mut ordinals: Vec<i64> = (1..=count).collect();
+        let Some((k, seed)) = self.sample else {
+            return (ordinals, Vec::new());
+        };
+        if k >= ordinals.len() {
+            return (ordinals, Vec::new());
+        }
+
+        // Move a random choice of k ordinals to the front.
+        let mut rng = Rng::new(seed);
+        for i in 0..k {
+            let j = i + rng.up_to((ordinals.len() - i - 1) as u64) as usize;
+            ordinals.swap(i, j);
+        }
+        let mut skipped = ordinals.split_off(k);
+        ordinals.sort_unstable();
+        skipped.sort_unstable();
+        (ordinals, skipped)
+    }
+
+    // See HIDDEN DOC in failpoint_state.rs.
+    //
+    // Returns true if no more runs should be made, after `runs` runs
+    // since `start`.
+    #[cfg(feature = "failpoint_enabled")]
+    #[doc(hidden)]
+    pub fn exhausted(&self, start: Instant, runs: usize) -> bool {
+        self.max_iterations.is_some_and(|n| runs >= n)
+            || self.time_budget.is_some_and(|b| start.elapsed() >= b)
+    }
+}
 
 pub struct CodePathResult<T, E> {
     pub expected_trigger_count: i64,
     pub trigger_count: i64,
+
+    /// The number of errors injected in all the iterations, which is
+    /// more than `trigger_count` if failpoints keep failing after they
+    /// are triggered.  See [`set_persistence()`](crate::set_persistence).
+    pub injected_count: i64,
+
     pub unexpected_result: Option<Result<T, E>>,
+
+    /// How the failpoints reached in a trigger iteration first
+    /// differed from those counted, if they did.  See
+    /// [`get_divergence()`](crate::get_divergence).
+    pub divergence: Option<String>,
+
+    /// The errors injected in each run of the code path that wasn't
+    /// explored further, as the ordinal and location of each failpoint
+    /// that injected one, in the order they were reached.  Without a
+    /// `depth`, each run injects a single error, unless failpoints keep
+    /// failing after they are triggered.
+    pub failure_sequences: Vec<Vec<(i64, Location)>>,
+
+    /// False if some runs were not made, because of [`Limits`] or
+    /// because a run with an unexpected result or a divergence stopped
+    /// the exploration.
+    pub complete: bool,
+
+    /// The ordinals of the errors to inject in the runs that were not
+    /// made, in ascending order.
+    pub skipped: Vec<Vec<i64>>,
+}
+
+// See HIDDEN DOC in failpoint_state.rs.
+//
+// What one iteration of `test_codepath!` found, when the iterations
+// are run in parallel.
+#[doc(hidden)]
+pub struct Iteration<T, E> {
+    // The result of the code path, if it wasn't the expected one.
+    pub unexpected: Option<Result<T, E>>,
+
+    // In the counting iteration, the number of failpoints counted.
+    pub count: i64,
+
+    // The number of failpoints reached.
+    pub reached: i64,
+
+    pub injected: i64,
+    pub divergence: Option<String>,
+
+    // The ordinals and locations of the failpoints that injected errors.
+    pub injected_locs: Vec<(i64, Location)>,
+}
+
+// The work shared by the threads running iterations in parallel.
+#[cfg(feature = "failpoint_enabled")]
+struct Work<T, E> {
+    // The errors to inject in the runs still to be made.
+    pending: Vec<Vec<i64>>,
+
+    // The number of runs made, and being made.
+    runs: usize,
+    running: usize,
+
+    // Set when a run found a problem, to stop the others.
+    stop: bool,
+
+    result: CodePathResult<T, E>,
+}
+
+#[cfg(feature = "failpoint_enabled")]
+impl<T, E> Work<T, E> {
+    // Merges the outcome of the run that injected `sequence`.
+    fn record(&mut self, sequence: Vec<i64>, it: Iteration<T, E>, max_depth: usize) {
+        if let Some(res) = it.unexpected {
+            log_if_verbose(
+                Verbosity::None,
+                format!(
+                    "Codepath did not fail in trigger mode for errors {sequence:?}.  Expected codepath to fail."
+                ),
+            );
+            self.result.unexpected_result.get_or_insert(res);
+            self.stop = true;
+            return;
+        }
+        if let Some(d) = it.divergence {
+            log_if_verbose(
+                Verbosity::None,
+                format!("Codepath is not deterministic for errors {sequence:?}: {d}"),
+            );
+            self.result.divergence.get_or_insert(d);
+            self.stop = true;
+            return;
+        }
+
+        self.result.trigger_count += 1;
+        self.result.injected_count += it.injected;
+
+        // Explore the failpoints on the error handling path, as the
+        // serial iterations do.
+        let last = sequence[sequence.len() - 1];
+        if sequence.len() < max_depth && it.reached > last {
+            self.result.expected_trigger_count += it.reached - last;
+            for n in (last + 1..=it.reached).rev() {
+                let mut s = sequence.clone();
+                s.push(n);
+                self.pending.push(s);
+            }
+        } else {
+            self.result.failure_sequences.push(it.injected_locs);
+        }
+    }
+}
+
+// See HIDDEN DOC in failpoint_state.rs.
+//
+// Runs the iterations of `test_codepath!` with `threads`.  `iteration`
+// runs one iteration, injecting errors at the ordinals it is given, or
+// counting if there are none.  The counting iteration runs on this
+// thread, and the trigger iterations on the others, each in its own
+// `Session`.
+#[cfg(feature = "failpoint_enabled")]
+#[doc(hidden)]
+pub fn run_iterations_parallel<T, E, F>(
+    threads: usize,
+    max_depth: usize,
+    limits: &Limits,
+    iteration: F,
+) -> CodePathResult<T, E>
+where
+    T: Send,
+    E: Send,
+    F: Fn(&[i64]) -> Iteration<T, E> + Sync,
+{
+    let counted = iteration(&[]);
+    if counted.unexpected.is_some() {
+        return CodePathResult {
+            expected_trigger_count: i64::MAX,
+            trigger_count: 0,
+            injected_count: 0,
+            unexpected_result: counted.unexpected,
+            divergence: None,
+            failure_sequences: Vec::new(),
+            complete: false,
+            skipped: Vec::new(),
+        };
+    }
+
+    let (selected, sampled_out) = limits.sample_ordinals(counted.count);
+    let work = Mutex::new(Work {
+        pending: selected.into_iter().rev().map(|n| vec![n]).collect(),
+        runs: 0,
+        running: 0,
+        stop: false,
+        result: CodePathResult {
+            expected_trigger_count: counted.count,
+            trigger_count: 0,
+            injected_count: 0,
+            unexpected_result: None,
+            divergence: None,
+            failure_sequences: Vec::new(),
+            complete: true,
+            skipped: sampled_out.into_iter().map(|n| vec![n]).collect(),
+        },
+    });
+    let cv = Condvar::new();
+    let start = Instant::now();
+
+    // The sessions collect the failpoints that inject errors if their
+    // parent does.
+    let collecting = collect_injected_locs(true);
+    let lock = || work.lock().unwrap_or_else(|e| e.into_inner());
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            scope.spawn(|| {
+                let _session = Session::new();
+                loop {
+                    let sequence = {
+                        let mut w = lock();
+                        loop {
+                            if w.stop {
+                                return;
+                            }
+                            if !w.pending.is_empty() && limits.exhausted(start, w.runs) {
+                                log_if_verbose(
+                                    Verbosity::Moderate,
+                                    format!(
+                                        "Exploration limit reached, skipping {} runs",
+                                        w.pending.len()
+                                    ),
+                                );
+                                let mut pending = std::mem::take(&mut w.pending);
+                                w.result.skipped.append(&mut pending);
+                            }
+                            if let Some(s) = w.pending.pop() {
+                                w.runs += 1;
+                                w.running += 1;
+                                break s;
+                            }
+                            // Another run may yet find more failpoints.
+                            if w.running == 0 {
+                                cv.notify_all();
+                                return;
+                            }
+                            w = cv.wait(w).unwrap_or_else(|e| e.into_inner());
+                        }
+                    };
+
+                    let it = iteration(&sequence);
+
+                    let mut w = lock();
+                    w.running -= 1;
+                    w.record(sequence, it, max_depth);
+                    cv.notify_all();
+                }
+            });
+        }
+    });
+    collect_injected_locs(collecting);
+
+    // A run that found a problem stopped the others, so the runs still
+    // to be made are skipped.
+    let work = work.into_inner().unwrap_or_else(|e| e.into_inner());
+    let mut result = work.result;
+    if !work.pending.is_empty() {
+        log_if_verbose(
+            Verbosity::Moderate,
+            format!("Exploration stopped, skipping {} runs", work.pending.len()),
+        );
+        result.skipped.extend(work.pending);
+    }
+    result
+        .failure_sequences
+        .sort_by(|a, b| a.iter().map(|(n, _)| n).cmp(b.iter().map(|(n, _)| n)));
+    result.skipped.sort();
+    result.complete = result.skipped.is_empty() && !work.stop;
+    log_event(FailpointEvent::Finished {
+        counted: result.expected_trigger_count,
+        triggered: result.trigger_count,
+    });
+    result
 }
 
 impl<T, E> CodePathResult<T, E> {
     pub fn success(&self) -> bool {
-        self.trigger_count == self.expected_trigger_count
+        self.trigger_count + self.skipped.len() as i64 == self.expected_trigger_count
+            && self.divergence.is_none()
     }
 }
 
@@ -31,7 +416,7 @@ where
 
         log_if_verbose(
             Verbosity::Moderate,
-            format!("************************************************************************"),
+            "************************************************************************".to_string(),
         );
         log_if_verbose(Verbosity::Moderate, format!("* Codepath:   {name}"));
 
@@ -45,14 +430,27 @@ where
             Verbosity::Moderate,
             format!("* Triggered:  {}", self.trigger_count),
         );
+        log_if_verbose(
+            Verbosity::Moderate,
+            format!("* Injected:   {}", self.injected_count),
+        );
         if let Some(unex) = &self.unexpected_result {
-            log_if_verbose(Verbosity::Moderate, format!("* Unexpected: {:?}", unex));
+            warn_if_verbose(Verbosity::Moderate, format!("* Unexpected: {:?}", unex));
+        }
+        if !self.complete {
+            log_if_verbose(
+                Verbosity::Moderate,
+                format!("* Skipped:    {}", self.skipped.len()),
+            );
+        }
+        if let Some(d) = &self.divergence {
+            warn_if_verbose(Verbosity::Moderate, format!("* Diverged:   {d}"));
         }
 
         log_if_verbose(Verbosity::Extreme, "*".to_string());
 
         let counted_locs = get_counted_locs();
-        log_if_verbose(Verbosity::Extreme, format!("* Counted Failpoints: "));
+        log_if_verbose(Verbosity::Extreme, "* Counted Failpoints: ".to_string());
 
         for (i, loc) in counted_locs.iter().enumerate() {
             log_if_verbose(
@@ -63,7 +461,7 @@ where
 
         log_if_verbose(Verbosity::Extreme, "*".to_string());
         let triggered_locs = get_triggered_locs();
-        log_if_verbose(Verbosity::Extreme, format!("* Triggered Failpoints:"));
+        log_if_verbose(Verbosity::Extreme, "* Triggered Failpoints:".to_string());
 
         for (i, loc) in triggered_locs.iter().enumerate() {
             log_if_verbose(
@@ -73,12 +471,15 @@ where
         }
         log_if_verbose(Verbosity::Extreme, "*".to_string());
 
-        let ok = if self.success() { "SUCCESS" } else { "FAILED" };
-        log_if_verbose(Verbosity::Moderate, format!("* Result:     {ok}"));
+        if self.success() {
+            log_if_verbose(Verbosity::Moderate, "* Result:     SUCCESS".to_string());
+        } else {
+            warn_if_verbose(Verbosity::Moderate, "* Result:     FAILED".to_string());
+        }
 
         log_if_verbose(
             Verbosity::Moderate,
-            format!("************************************************************************"),
+            "************************************************************************".to_string(),
         );
     }
 }
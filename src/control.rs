@@ -0,0 +1,362 @@
+//! Runtime control of failpoints over a Unix domain socket.
+//!
+//! Long running processes can't easily call [`start_counter()`] or
+//! [`start_trigger()`] from a test harness that lives in another
+//! process.  The control server listens on a Unix domain socket and
+//! lets another process drive the failpoints instead.
+//!
+//! Enable it with the `control` feature:
+//!
+//! ```toml
+//! [dependencies]
+//! failpoint = { version = "4.0", features = ["control"] }
+//! ```
+//!
+//! Then start the server somewhere near the start of `main()`.  The
+//! server stops and removes the socket when the returned
+//! [`ControlServer`] is dropped.
+//!
+//! ```rust,no_run
+//! # fn main() -> std::io::Result<()> {
+//! let _server = failpoint::control::start_control_server("/tmp/my_service.failpoint")?;
+//!
+//! // Run the service ...
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Protocol
+//!
+//! The client sends one command per line.  The server answers each
+//! command with zero or more lines of output, followed by a line
+//! containing `OK`, or a line containing `ERR` and a message if the
+//! command failed.
+//!
+//! | Command              | Effect                                            |
+//! |----------------------|---------------------------------------------------|
+//! | `count`              | [`start_counter()`]                               |
+//! | `trigger N`          | [`start_trigger(N)`](start_trigger())             |
+//! | `trigger-named DESC` | [`start_trigger_named(DESC)`](start_trigger_named()) |
+//! | `on`                 | Activate failpoints                               |
+//! | `off`                | Deactivate failpoints, they will pass results through |
+//! | `verbosity LEVEL`    | [`set_verbosity()`], `LEVEL` is `none`, `moderate` or `extreme` |
+//! | `status`             | Print the active flag, mode, counter and trigger  |
+//! | `list`               | Print the counted failpoints, see [`get_counted_locs()`] |
+//! | `tail`               | Print log messages as they are produced, see below |
+//!
+//! Counted failpoints are only recorded when the verbosity is
+//! `extreme`, so send `verbosity extreme` before `count` if you want
+//! to `list` them afterwards.
+//!
+//! `tail` is answered with `OK` straight away, and then every message
+//! sent to the logger is written to the connection, one per line,
+//! until the client disconnects.  Messages are only produced when the
+//! verbosity is at least `moderate`.
+//!
+//! For example:
+//!
+//! ```text
+//! > status
+//! < active true
+//! < mode count
+//! < count 2
+//! < OK
+//! > trigger 3
+//! < OK
+//! > bogus
+//! < ERR unknown command "bogus"
+//! ```
+//!
+//! [`ControlClient`] speaks this protocol, and the `failpoint`
+//! command line tool (built with the `cli` feature) wraps it.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use crate::failpoint_state::add_log_tap;
+use crate::{
+    Mode, Verbosity, get_counted_locs, lock_state, set_active, set_verbosity, start_counter,
+    start_trigger, start_trigger_named,
+};
+
+/// A running control server.
+///
+/// The server stops listening and removes its socket when this is
+/// dropped, or when [`ControlServer::shutdown()`] is called.
+pub struct ControlServer {
+    path: PathBuf,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ControlServer {
+    /// The path of the socket the server is listening on.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stops the server and removes the socket.
+    pub fn shutdown(self) {
+        drop(self)
+    }
+
+    fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.shutdown.store(true, Ordering::SeqCst);
+            // Wake up the accept loop so it notices the shutdown flag.
+            _ = UnixStream::connect(&self.path);
+            _ = thread.join();
+            _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Starts a control server listening on a Unix domain socket at
+/// `path`.
+///
+/// Each client connection is handled on its own thread, so several
+/// clients may be connected at once.  See the [module
+/// documentation](self) for the protocol.
+///
+/// # Errors
+///
+/// Returns an error if the socket can't be bound, for example
+/// because `path` already exists.
+pub fn start_control_server<P: AsRef<Path>>(path: P) -> io::Result<ControlServer> {
+    let path = path.as_ref().to_path_buf();
+    let listener = UnixListener::bind(&path)?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let thread = {
+        let shutdown = shutdown.clone();
+        thread::Builder::new()
+            .name("failpoint-control".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        thread::spawn(move || {
+                            _ = handle_client(stream);
+                        });
+                    }
+                }
+            })?
+    };
+
+    Ok(ControlServer {
+        path,
+        shutdown,
+        thread: Some(thread),
+    })
+}
+
+fn handle_client(stream: UnixStream) -> io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line == "tail" {
+            return tail(writer);
+        }
+
+        match execute(line) {
+            Ok(output) => {
+                for l in output {
+                    writeln!(writer, "{l}")?;
+                }
+                writeln!(writer, "OK")?;
+            }
+            Err(msg) => writeln!(writer, "ERR {msg}")?,
+        }
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+// Streams log messages to the client until it disconnects.
+fn tail(mut writer: UnixStream) -> io::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    add_log_tap(tx);
+
+    writeln!(writer, "OK")?;
+    writer.flush()?;
+
+    for msg in rx {
+        writeln!(writer, "{msg}")?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+// Runs a single command and returns the lines of output.
+fn execute(line: &str) -> Result<Vec<String>, String> {
+    let (cmd, arg) = match line.split_once(char::is_whitespace) {
+        Some((cmd, arg)) => (cmd, arg.trim()),
+        None => (line, ""),
+    };
+
+    match (cmd, arg) {
+        ("count", "") => {
+            start_counter();
+            Ok(Vec::new())
+        }
+        ("trigger", n) => {
+            let n = n
+                .parse::<i64>()
+                .map_err(|_| format!("bad trigger ordinal \"{n}\""))?;
+            if n < 1 {
+                return Err(format!("trigger ordinal must be at least 1, got {n}"));
+            }
+            start_trigger(n);
+            Ok(Vec::new())
+        }
+        ("trigger-named", "") => Err("trigger-named needs a description".to_string()),
+        ("trigger-named", desc) => {
+            start_trigger_named(desc);
+            Ok(Vec::new())
+        }
+        ("on", "") => {
+            set_active(true);
+            Ok(Vec::new())
+        }
+        ("off", "") => {
+            set_active(false);
+            Ok(Vec::new())
+        }
+        ("verbosity", level) => {
+            let v = match level {
+                "none" => Verbosity::None,
+                "moderate" => Verbosity::Moderate,
+                "extreme" => Verbosity::Extreme,
+                _ => return Err(format!("bad verbosity \"{level}\"")),
+            };
+            set_verbosity(v);
+            Ok(Vec::new())
+        }
+        ("status", "") => Ok(status()),
+        ("list", "") => Ok(get_counted_locs()
+            .iter()
+            .enumerate()
+            .map(|(i, loc)| format!("{} {}", i + 1, loc.format()))
+            .collect()),
+        _ => Err(format!("unknown command \"{line}\"")),
+    }
+}
+
+fn status() -> Vec<String> {
+    let g = lock_state();
+    let mut out = vec![
+        format!("active {}", g.active),
+        format!("mode {}", g.mode.name()),
+        format!("count {}", g.counter),
+    ];
+    if g.mode == Mode::Trigger {
+        out.push(format!("trigger {}", g.trigger));
+        if let Some(ref d) = g.trigger_desc {
+            out.push(format!("trigger-named {d}"));
+        }
+    }
+    out
+}
+
+/// A client for a [`ControlServer`].
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use failpoint::control::ControlClient;
+///
+/// # fn main() -> std::io::Result<()> {
+/// let mut client = ControlClient::connect("/tmp/my_service.failpoint")?;
+/// client.command("trigger 2")?;
+/// for line in client.command("status")? {
+///     println!("{line}");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct ControlClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl ControlClient {
+    /// Connects to the control server listening at `path`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let writer = UnixStream::connect(path)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Self { reader, writer })
+    }
+
+    /// Sends `command` to the server and returns its output lines.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the connection fails, or an error of kind
+    /// [`io::ErrorKind::Other`] holding the server's message if the
+    /// server answered `ERR`.
+    pub fn command(&mut self, command: &str) -> io::Result<Vec<String>> {
+        writeln!(self.writer, "{}", command.trim())?;
+        self.writer.flush()?;
+
+        let mut output = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            if line == "OK" {
+                return Ok(output);
+            }
+            if let Some(msg) = line.strip_prefix("ERR") {
+                return Err(io::Error::other(msg.trim().to_string()));
+            }
+            output.push(line);
+        }
+    }
+
+    /// Calls `f` with each log message produced by the server's
+    /// process, until the server goes away or `f` returns `false`.
+    pub fn tail<F: FnMut(&str) -> bool>(mut self, mut f: F) -> io::Result<()> {
+        self.command("tail")?;
+        loop {
+            let line = match self.read_line() {
+                Ok(line) => line,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            if !f(&line) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "control server closed the connection",
+            ));
+        }
+        Ok(line.trim_end().to_string())
+    }
+}
@@ -0,0 +1,63 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::Location;
+
+/// The payload that a triggered [`crashpoint!`](crate::crashpoint!)
+/// unwinds with.
+///
+/// [`test_codepath!`](crate::test_codepath!) catches it and treats the
+/// iteration as a crash of the process at the crash point.  Code that
+/// catches panics itself should let it continue unwinding, see
+/// [`Crash::from_payload()`].
+#[derive(Debug, Clone)]
+pub struct Crash {
+    /// Where the crash happened.
+    pub loc: Location,
+}
+
+impl Crash {
+    /// Returns the crash if `payload`, as returned by
+    /// [`std::panic::catch_unwind()`], was produced by a crash point.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::panic;
+    ///
+    /// use failpoint::{Crash, crashpoint};
+    ///
+    /// failpoint::start_trigger(1);
+    /// let payload = panic::catch_unwind(|| crashpoint!("commit")).unwrap_err();
+    /// let crash = Crash::from_payload(payload.as_ref()).unwrap();
+    /// assert_eq!(crash.loc.desc.as_deref(), Some("commit"));
+    /// ```
+    pub fn from_payload(payload: &(dyn Any + Send)) -> Option<&Crash> {
+        payload.downcast_ref::<Crash>()
+    }
+}
+
+// See HIDDEN DOC in failpoint_state.rs.
+//
+// Unwinds with a `Crash` payload.  `resume_unwind()` does not run the
+// panic hook, so no panic message is printed.  The state lock must
+// not be held, or it would be poisoned.
+#[doc(hidden)]
+pub fn crash(loc: Location) -> ! {
+    panic::resume_unwind(Box::new(Crash { loc }))
+}
+
+// See HIDDEN DOC in failpoint_state.rs.
+//
+// Runs `f`, returning the crash if a crash point was triggered.  Any
+// other panic continues to unwind.
+#[doc(hidden)]
+pub fn catch_crash<R>(f: impl FnOnce() -> R) -> Result<R, Crash> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(r) => Ok(r),
+        Err(payload) => match payload.downcast::<Crash>() {
+            Ok(crash) => Err(*crash),
+            Err(payload) => panic::resume_unwind(payload),
+        },
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+
+use crate::{Location, Verbosity};
+
+/// Something that happened while running failpoints or
+/// `test_codepath!`, as passed to an [`EventLogger`].
+///
+/// The [`Display`](fmt::Display) implementation produces the messages
+/// that are passed to a string [`Logger`](crate::Logger).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum FailpointEvent {
+    /// A failpoint was counted in "Count" mode.
+    Counted {
+        loc: Location,
+        /// The position of the failpoint in the code path, starting
+        /// at 1.
+        ordinal: i64,
+    },
+
+    /// A failpoint was triggered in "Trigger" mode.
+    Triggered {
+        loc: Location,
+        ordinal: i64,
+        /// The injected error, formatted with `{:?}`.
+        error: String,
+    },
+
+    /// A failpoint was triggered in "Trigger" mode and injected
+    /// something other than an error, such as a short read.
+    Injected {
+        loc: Location,
+        ordinal: i64,
+        /// A description of what was injected.
+        fault: String,
+    },
+
+    /// A failpoint was triggered, but the result it was given was
+    /// already an error.  The error is replaced by the injected one.
+    Unexpected {
+        loc: Location,
+        ordinal: i64,
+        /// The error the failpoint was given, formatted with `{:?}`.
+        error: String,
+    },
+
+    /// `test_codepath!` is starting an iteration.
+    IterationStart {
+        /// The iteration number, starting at 0 for the counting
+        /// iteration.
+        iteration: i64,
+        /// The failpoint that will be triggered, or 0 in the counting
+        /// iteration.
+        trigger: i64,
+    },
+
+    /// `test_codepath!` has finished.
+    Finished {
+        /// The number of failpoints counted.
+        counted: i64,
+        /// The number of failpoints triggered.
+        triggered: i64,
+    },
+
+    /// Any other message.
+    Message {
+        /// The verbosity needed to produce the message.
+        level: Verbosity,
+        /// True if the message reports a failure.
+        failure: bool,
+        text: String,
+    },
+}
+
+impl FailpointEvent {
+    /// The verbosity needed for the event to be logged.
+    pub fn verbosity(&self) -> Verbosity {
+        match self {
+            FailpointEvent::IterationStart { .. } => Verbosity::Extreme,
+            FailpointEvent::Message { level, .. } => *level,
+            _ => Verbosity::Moderate,
+        }
+    }
+
+    /// True if the event reports something that went wrong.
+    /// Messages that are logged even at `Verbosity::None` are always
+    /// failures.
+    pub fn is_failure(&self) -> bool {
+        match self {
+            FailpointEvent::Unexpected { .. } => true,
+            FailpointEvent::Message { level, failure, .. } => *failure || *level == Verbosity::None,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for FailpointEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailpointEvent::Counted { loc, .. } => write!(f, "Found {}", loc.format()),
+            FailpointEvent::Triggered { loc, error, .. } => {
+                write!(f, "Triggered {} injecting Err({error})", loc.format())
+            }
+            FailpointEvent::Injected { loc, fault, .. } => {
+                write!(f, "Triggered {} injecting {fault}", loc.format())
+            }
+            FailpointEvent::Unexpected { loc, error, .. } => {
+                write!(f, "Unexpected error in {} got Err({error})", loc.format())
+            }
+            FailpointEvent::IterationStart { trigger: 0, .. } => {
+                write!(f, "Testing codepath in COUNT mode")
+            }
+            FailpointEvent::IterationStart { trigger, .. } => {
+                write!(
+                    f,
+                    "Testing codepath in TRIGGER mode, will trigger error {trigger}"
+                )
+            }
+            FailpointEvent::Finished { counted, triggered } => {
+                write!(f, "Triggered {triggered} of {counted} errors")
+            }
+            FailpointEvent::Message { text, .. } => write!(f, "{text}"),
+        }
+    }
+}
+
+/// A function that receives each [`FailpointEvent`].  See
+/// [`set_event_logger()`](crate::set_event_logger).
+pub type EventLogger = Box<dyn Fn(&FailpointEvent) + Send + Sync>;
@@ -67,7 +67,7 @@
 #[macro_export]
 macro_rules! failpoint {
     ($res: ident, $err: expr, $desc: expr) => {{
-	failpoint!(@internal $res, $err, Some($desc))
+	failpoint!(@internal $res, $err, Some(::std::borrow::Cow::Borrowed($desc)))
     }};
 
     ($res: ident, $err: expr) => {{
@@ -79,7 +79,7 @@ macro_rules! failpoint {
             const CRATE_NAME: Option<&'static str> = core::option_env!("CARGO_CRATE_NAME");
             let res_ = $res;
 
-            use failpoint::{Mode, lock_state};
+            use failpoint::lock_state;
             let mut g = lock_state();
 	    if g.active {
 		let loc_ = failpoint::Location{
@@ -89,25 +89,18 @@ macro_rules! failpoint {
 		    desc: $desc_opt,
 		};
 
-		if g.mode == Mode::Count {
-                    g.counter += 1;
-		    g.report_count(&loc_);
-                    res_
+		if g.should_trigger(&loc_) {
+		    if res_.is_err() {
+			let unexp_err_ = res_.unwrap_err();
+			let debug_unexp_err_: &dyn std::fmt::Debug = &unexp_err_;
+			g.report_unexpected_failure(&loc_, debug_unexp_err_);
+		    }
+		    let err_ = $err;
+		    let debug_err_: &dyn std::fmt::Debug = &err_;
+		    g.report_trigger(&loc_, debug_err_);
+		    Err(err_)
 		} else {
-                    g.trigger -= 1;
-                    if g.trigger == 0 {
-			if res_.is_err() {
-			    let unexp_err_ = res_.unwrap_err();
-			    let debug_unexp_err_: &dyn std::fmt::Debug = &unexp_err_;
-                            g.report_unexpected_failure(&loc_, debug_unexp_err_);
-			}
-			let err_ = $err;
-			let debug_err_: &dyn std::fmt::Debug = &err_;
-			g.report_trigger(&loc_, debug_err_);
-			Err(err_)
-                    } else {
-			res_
-                    }
+		    res_
 		}
             } else {
 		res_
@@ -129,3 +122,341 @@ macro_rules! failpoint {
         $res
     }};
 }
+
+/// Injects corrupted data into a successful result.
+///
+/// Not every fault is an error: sometimes a read succeeds but
+/// returns bad data.  `failpoint_corrupt!` is a failpoint that, when
+/// triggered, leaves the result `Ok` but applies `$mutate` to the
+/// value, so that checksums and other corruption detection can be
+/// tested.  It is counted and triggered like [`failpoint!`].
+///
+/// # Arguments
+///
+/// * `$res` - An identifier that has the type `Result<T, E>`
+/// * `$mutate` - A closure taking `&mut T` that corrupts the value,
+///   for example by flipping a bit, truncating or zeroing it
+/// * `$desc` - An optional description string for logging
+///
+/// If the result is already an error when the failpoint is triggered
+/// it can't be corrupted, so it is returned unchanged and reported
+/// as an unexpected failure.
+///
+/// # Examples
+///
+/// ```rust
+/// use failpoint::{failpoint_corrupt, test_codepath};
+///
+/// fn read_block() -> Result<Vec<u8>, String> {
+///     let data = vec![1, 2, 3];
+///     let sum = data.iter().fold(0u8, |a, b| a.wrapping_add(*b));
+///     let res: Result<Vec<u8>, String> = Ok(data.iter().copied().chain([sum]).collect());
+///     failpoint_corrupt!(res, |v| v[0] ^= 0x80, "read block")
+/// }
+///
+/// fn read_checked() -> Result<Vec<u8>, String> {
+///     let mut block = read_block()?;
+///     let sum = block.pop().unwrap();
+///     if block.iter().fold(0u8, |a, b| a.wrapping_add(*b)) != sum {
+///         return Err("checksum mismatch".to_string());
+///     }
+///     Ok(block)
+/// }
+///
+/// let res = test_codepath! {
+///     codepath {
+///         read_checked()
+///     }
+/// };
+///
+/// // The corruption was detected.
+/// assert!(res.success());
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+#[macro_export]
+macro_rules! failpoint_corrupt {
+    ($res: ident, $mutate: expr, $desc: expr) => {{
+	failpoint_corrupt!(@internal $res, $mutate, Some(::std::borrow::Cow::Borrowed($desc)))
+    }};
+
+    ($res: ident, $mutate: expr) => {{
+	failpoint_corrupt!(@internal $res, $mutate, None)
+    }};
+
+    (@internal $res: ident, $mutate: expr, $desc_opt: expr) => {{
+        {
+            const CRATE_NAME: Option<&'static str> = core::option_env!("CARGO_CRATE_NAME");
+            let mut res_ = $res;
+
+            use failpoint::lock_state;
+            let mut g = lock_state();
+	    if g.active {
+		let loc_ = failpoint::Location{
+		    crate_name: CRATE_NAME,
+		    file_name: file!(),
+		    line_no: line!(),
+		    desc: $desc_opt,
+		};
+
+		if g.should_trigger(&loc_) {
+		    match res_ {
+			Ok(ref mut v_) => {
+			    g.report_fault(&loc_, "a corrupted value");
+			    // Release the lock, in case the mutation reaches a
+			    // failpoint.
+			    drop(g);
+			    failpoint::corrupt_with(v_, $mutate);
+			}
+			Err(ref e_) => {
+			    let debug_unexp_err_: &dyn std::fmt::Debug = e_;
+			    g.report_unexpected_failure(&loc_, debug_unexp_err_);
+			}
+		    }
+		}
+	    }
+	    res_
+	}
+    }};
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[macro_export]
+macro_rules! failpoint_corrupt {
+    ($res: ident, $mutate: expr, $desc: expr) => {{ failpoint_corrupt!($res, $mutate) }};
+
+    ($res: ident, $mutate: expr) => {{
+        let mut res_ = $res;
+        if false {
+            if let Ok(ref mut v_) = res_ {
+                failpoint::corrupt_with(v_, $mutate);
+            }
+        }
+        res_
+    }};
+}
+
+/// Runs a side effect at a failpoint.
+///
+/// `failpoint_action!` is a failpoint that, when triggered, calls the
+/// closure `$action`, which takes no arguments.  It can do whatever
+/// the test needs at that precise point, such as deleting a file,
+/// dropping a lock or signalling another thread, so races and
+/// concurrent modifications can be reproduced deterministically.  It
+/// is counted, triggered and logged like [`failpoint!`].
+///
+/// The closure is run after the failpoint state is unlocked, so it
+/// may itself reach failpoints.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::cell::RefCell;
+///
+/// use failpoint::{failpoint_action, start_trigger_named};
+///
+/// let cache = RefCell::new(vec![1, 2, 3]);
+///
+/// let lookup = |i: usize| {
+///     // Another thread might evict the cache here.
+///     failpoint_action!(|| cache.borrow_mut().clear(), "evict");
+///     cache.borrow().get(i).copied()
+/// };
+///
+/// assert_eq!(lookup(0), Some(1));
+/// start_trigger_named("evict");
+/// assert_eq!(lookup(0), None);
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+#[macro_export]
+macro_rules! failpoint_action {
+    ($action: expr, $desc: expr) => {{
+	failpoint_action!(@internal $action, Some(::std::borrow::Cow::Borrowed($desc)))
+    }};
+
+    ($action: expr) => {{
+	failpoint_action!(@internal $action, None)
+    }};
+
+    (@internal $action: expr, $desc_opt: expr) => {{
+	const CRATE_NAME: Option<&'static str> = core::option_env!("CARGO_CRATE_NAME");
+
+	use failpoint::lock_state;
+	let mut g = lock_state();
+	if g.active {
+	    let loc_ = failpoint::Location{
+		crate_name: CRATE_NAME,
+		file_name: file!(),
+		line_no: line!(),
+		desc: $desc_opt,
+	    };
+
+	    if g.should_trigger(&loc_) {
+		g.report_fault(&loc_, "an action");
+		drop(g);
+		($action)();
+	    }
+	}
+    }};
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[macro_export]
+macro_rules! failpoint_action {
+    ($action: expr, $desc: expr) => {{
+        let _ = $action;
+    }};
+
+    ($action: expr) => {{
+        let _ = $action;
+    }};
+}
+
+/// Blocks the thread at this point while the test interleaves
+/// another.
+///
+/// `failpoint_pause!("desc")` does nothing unless its pause point has
+/// been armed with [`arm_pause()`](crate::arm_pause).  The next
+/// thread to reach an armed pause point blocks, the test waits for it
+/// with [`wait_until_paused()`](crate::wait_until_paused), does
+/// whatever should race with the paused thread, and lets it continue
+/// with [`release()`](crate::release).
+///
+/// Pause points are identified by their description.  They are not
+/// counted or triggered, so adding one doesn't change the ordinals of
+/// the failpoints around it.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::sync::{Arc, Mutex};
+/// use std::thread;
+///
+/// use failpoint::{arm_pause, failpoint_pause, release, wait_until_paused};
+///
+/// let balance = Arc::new(Mutex::new(100));
+///
+/// // Withdraws with a read-modify-write that isn't atomic.
+/// fn withdraw(balance: &Mutex<i32>, amount: i32) {
+///     let read = *balance.lock().unwrap();
+///     failpoint_pause!("withdraw");
+///     *balance.lock().unwrap() = read - amount;
+/// }
+///
+/// arm_pause("withdraw");
+/// let b = balance.clone();
+/// let t = thread::spawn(move || withdraw(&b, 30));
+///
+/// // The first withdrawal has read the balance; make a second one.
+/// wait_until_paused("withdraw");
+/// withdraw(&balance, 20);
+/// release("withdraw");
+/// t.join().unwrap();
+///
+/// // The second withdrawal was lost.
+/// assert_eq!(*balance.lock().unwrap(), 70);
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+#[macro_export]
+macro_rules! failpoint_pause {
+    ($desc: expr) => {{
+        const CRATE_NAME: Option<&'static str> = core::option_env!("CARGO_CRATE_NAME");
+
+        failpoint::pause_at(failpoint::Location {
+            crate_name: CRATE_NAME,
+            file_name: file!(),
+            line_no: line!(),
+            desc: Some(::std::borrow::Cow::Borrowed($desc)),
+        })
+    }};
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[macro_export]
+macro_rules! failpoint_pause {
+    ($desc: expr) => {{
+        let _ = $desc;
+    }};
+}
+
+/// Simulates the process crashing at this point.
+///
+/// A crash point is a failpoint that takes no result.  It is counted
+/// like any other failpoint, and when it is triggered it unwinds the
+/// stack with a [`Crash`](crate::Crash) payload instead of returning
+/// an error, so nothing after it runs, as if the process had been
+/// killed with `kill -9`.  No panic message is printed.
+///
+/// [`test_codepath!`](crate::test_codepath!) catches the crash and
+/// runs its `recover` block, so recovery can be tested after a crash
+/// at every step of a multi-step operation.  The crash can't be
+/// caught if the crate is built with `panic = "abort"`.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::cell::RefCell;
+///
+/// use failpoint::{crashpoint, test_codepath};
+///
+/// // A commit that writes the data and then marks it as committed.
+/// fn commit(log: &RefCell<Vec<&'static str>>) -> Result<(), String> {
+///     log.borrow_mut().push("data");
+///     crashpoint!("after data");
+///     log.borrow_mut().push("commit");
+///     crashpoint!("after commit");
+///     Ok(())
+/// }
+///
+/// let log = RefCell::new(Vec::new());
+/// let res = test_codepath! {
+///     before {
+///         log.borrow_mut().clear();
+///     };
+///     codepath {
+///         commit(&log)
+///     };
+///     recover {
+///         // Recovery discards uncommitted data.
+///         let mut log = log.borrow_mut();
+///         if log.last() != Some(&"commit") {
+///             log.clear();
+///         }
+///     }
+/// };
+///
+/// assert!(res.success());
+/// assert_eq!(res.expected_trigger_count, 2);
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+#[macro_export]
+macro_rules! crashpoint {
+    ($desc: expr) => {{
+        const CRATE_NAME: Option<&'static str> = core::option_env!("CARGO_CRATE_NAME");
+
+        use failpoint::lock_state;
+        let mut g = lock_state();
+        if g.active {
+            let loc_ = failpoint::Location {
+                crate_name: CRATE_NAME,
+                file_name: file!(),
+                line_no: line!(),
+                desc: Some(::std::borrow::Cow::Borrowed($desc)),
+            };
+
+            if g.should_trigger(&loc_) {
+                g.report_fault(&loc_, "a crash");
+                // Release the lock so unwinding doesn't poison it.
+                drop(g);
+                failpoint::crash(loc_);
+            }
+        }
+    }};
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[macro_export]
+macro_rules! crashpoint {
+    ($desc: expr) => {{
+        let _ = $desc;
+    }};
+}
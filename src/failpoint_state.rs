@@ -8,11 +8,34 @@ pub fn is_enabled() -> bool {
     false
 }
 
+#[cfg(feature = "failpoint_enabled")]
+use std::cell::Cell;
+#[cfg(feature = "failpoint_enabled")]
+use std::collections::HashMap;
+#[cfg(feature = "failpoint_enabled")]
+use std::mem::ManuallyDrop;
+#[cfg(feature = "failpoint_enabled")]
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "failpoint_enabled")]
+use std::sync::mpsc::Sender;
 #[cfg(feature = "failpoint_enabled")]
 use std::sync::{LazyLock, Mutex, MutexGuard};
 
+use std::borrow::Cow;
 use std::fmt::Debug;
+use std::ops::RangeInclusive;
 
+use crate::failpoint_event::EventLogger;
+#[cfg(feature = "failpoint_enabled")]
+use crate::failpoint_event::FailpointEvent;
+#[cfg(feature = "failpoint_enabled")]
+use crate::interleave;
+#[cfg(feature = "failpoint_enabled")]
+use crate::replay::Decision;
+#[cfg(feature = "failpoint_enabled")]
+use crate::rng::Rng;
+
+/// A function that receives each log message.  See [`set_logger()`].
 pub type Logger = Box<dyn Fn(String) + Send + Sync>;
 
 // HIDDEN DOC:
@@ -28,6 +51,16 @@ pub enum Mode {
     Trigger,
 }
 
+#[cfg(feature = "failpoint_enabled")]
+impl Mode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Count => "count",
+            Mode::Trigger => "trigger",
+        }
+    }
+}
+
 /// How verbose to be and how much information to collect while
 /// running.
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
@@ -45,18 +78,20 @@ pub enum Verbosity {
 }
 
 /// A Location where a failpoint is counted or triggered.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Location {
     pub crate_name: Option<&'static str>,
     pub file_name: &'static str,
     pub line_no: u32,
-    pub desc: Option<&'static str>,
+    /// The failpoint's description.  Since 4.0 this may be made at
+    /// run time, as the [`fs`](crate::fs) failpoints' are.
+    pub desc: Option<Cow<'static, str>>,
 }
 
 impl Location {
     pub fn format(&self) -> String {
         let file_ref = self.format_file_ref();
-        if let Some(d) = self.desc {
+        if let Some(ref d) = self.desc {
             format!("Failpoint \"{d}\" at {file_ref}")
         } else {
             format!("Failpoint at {file_ref}")
@@ -72,6 +107,46 @@ impl Location {
     }
 }
 
+/// What a failpoint does after it has been triggered.  See
+/// [`set_persistence()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Persistence {
+    /// Later hits of the failpoint pass through.
+    #[default]
+    Once,
+
+    /// Every later hit of the failpoint fails too.
+    Always,
+
+    /// The next `n` hits of the failpoint fail too.
+    Hits(u64),
+}
+
+// Identifies a failpoint by where it is.
+#[cfg(feature = "failpoint_enabled")]
+type LocKey = (Option<&'static str>, &'static str, u32);
+
+#[cfg(feature = "failpoint_enabled")]
+fn loc_key(loc: &Location) -> LocKey {
+    (loc.crate_name, loc.file_name, loc.line_no)
+}
+
+// Identifies a failpoint by where it is and a hash of its
+// description, since the wrappers in `fs`, `io`, `net` and `mpsc`
+// reach a failpoint at one place for every path or peer, and tell
+// them apart by description.
+#[cfg(feature = "failpoint_enabled")]
+type HitKey = (LocKey, u64);
+
+#[cfg(feature = "failpoint_enabled")]
+fn hit_key(loc: &Location) -> HitKey {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    let mut h = DefaultHasher::new();
+    loc.desc.hash(&mut h);
+    (loc_key(loc), h.finish())
+}
+
 #[cfg(feature = "failpoint_enabled")]
 #[doc(hidden)]
 pub struct Inner {
@@ -81,13 +156,81 @@ pub struct Inner {
 
     pub counter: i64,
 
-    logger: Option<Logger>,
+    // The number of failpoints reached since the last call to
+    // `start_counter()` or `start_trigger()`, in either mode.
+    pub ordinal: i64,
+
+    logger: Option<EventLogger>,
+    log_taps: Vec<Sender<String>>,
     verbosity: Verbosity,
 
     pub trigger: i64,
+    pub trigger_desc: Option<String>,
+
+    // When set, with `trigger_desc`, the hits of the failpoint that
+    // trigger, instead of only the first.
+    pub trigger_hits: Option<RangeInclusive<u64>>,
+
+    // When not empty, the ordinals of the failpoints that trigger,
+    // instead of `trigger`.
+    pub trigger_sequence: Vec<i64>,
+
+    // The number of times each failpoint has been reached since the
+    // last call to `start_counter()` or `start_trigger()`, in the
+    // order they were first reached, and an index by location and
+    // description.
+    hits: Vec<(Location, u64)>,
+    hit_index: HashMap<HitKey, usize>,
+
+    // What happens to a failpoint after it is triggered, and while it
+    // keeps failing, which failpoint and how many more hits fail.
+    persistence: Persistence,
+    persisting: Option<(HitKey, u64)>,
+
+    // The number of errors injected since the last call to
+    // `start_trigger()`.
+    injected: i64,
+
+    // The failpoints reached since the last call to
+    // `start_counter()`, in order, as indexes into `path_locs`, the
+    // distinct failpoints among them, and where a trigger's path first
+    // differed from it.  After `start_counter()`, `path_locs` grows with
+    // `hits`, so the two share indexes and reaching a failpoint that
+    // has been reached before doesn't clone its location.
+    path: Vec<usize>,
+    path_locs: Vec<(HitKey, Location)>,
+    divergence: Option<String>,
 
     pub counted_locs: Vec<Location>,
     pub triggered_locs: Vec<Location>,
+
+    // Chooses random faults, such as the length of a torn write.  It
+    // is reseeded from `seed` at the start of each count or trigger,
+    // so every iteration makes the same choices.
+    pub(crate) seed: u64,
+    pub(crate) rng: Rng,
+
+    // Set in a child process run by `CrashTest`, so that the triggered
+    // failpoint aborts the process.
+    pub(crate) abort_on_trigger: bool,
+
+    // While collecting them, the ordinals and locations of the
+    // failpoints that injected faults since the start of the run.
+    pub(crate) injected_locs: Option<Vec<(i64, Location)>>,
+
+    // While minimizing, the failpoints that inject their faults, each
+    // with the hit of it that does, instead of those triggered.
+    pub(crate) inject_at: Option<Vec<(Decision, u64)>>,
+
+    // The decisions made since the start of the run, while recording,
+    // and the decisions to make, while replaying.  A replay that
+    // diverges records why, and injects nothing more.
+    pub(crate) recording: Option<Vec<Decision>>,
+    pub(crate) replay: Option<Vec<Decision>>,
+    pub(crate) replay_error: Option<String>,
+
+    // In a `Session`, the state that events are passed on to.
+    parent: Option<&'static State>,
 }
 
 #[cfg(feature = "failpoint_enabled")]
@@ -98,54 +241,389 @@ impl Default for Inner {
             mode: Mode::Count,
             counter: 0,
 
+            ordinal: 0,
+
             logger: None,
+            log_taps: Vec::new(),
             verbosity: Verbosity::None,
 
             trigger: i64::MAX,
+            trigger_desc: None,
+            trigger_hits: None,
+            trigger_sequence: Vec::new(),
+
+            hits: Vec::new(),
+            hit_index: HashMap::new(),
+
+            persistence: Persistence::Once,
+            persisting: None,
+            injected: 0,
+
+            path: Vec::new(),
+            path_locs: Vec::new(),
+            divergence: None,
 
             counted_locs: Vec::new(),
             triggered_locs: Vec::new(),
+
+            seed: 0,
+            rng: Rng::new(0),
+
+            abort_on_trigger: false,
+
+            injected_locs: None,
+            inject_at: None,
+
+            recording: None,
+            replay: None,
+            replay_error: None,
+
+            parent: None,
         }
     }
 }
 
 #[cfg(feature = "failpoint_enabled")]
 impl Inner {
-    pub fn report_count(&mut self, loc: &Location) {
-        if self.verbosity >= Verbosity::Moderate {
-            if let Some(ref log) = self.logger {
-                let loc_str = loc.format();
-                let msg = format!("Found {loc_str}");
-                log(msg);
+    /// Called each time an active failpoint at `loc` is reached.
+    /// Counts the failpoint in "Count" mode, and in "Trigger" mode
+    /// returns true if the failpoint should inject its error.
+    pub fn should_trigger(&mut self, loc: &Location) -> bool {
+        self.ordinal += 1;
+        interleave::note_yield_point();
+        let key = hit_key(loc);
+        let (i, hit) = self.count_hit(key, loc);
+
+        let triggered = if self.replay.is_some() {
+            self.check_replay(loc)
+        } else if let Some(ref at) = self.inject_at {
+            at.iter().any(|(d, h)| *h == hit && d.matches(loc))
+        } else if self.mode == Mode::Count {
+            self.counter += 1;
+            let i = match self.path_locs.get(i) {
+                Some((k, _)) if *k == key => i,
+                _ => {
+                    self.path_locs.push((key, loc.clone()));
+                    self.path_locs.len() - 1
+                }
+            };
+            self.path.push(i);
+            self.report_count(loc);
+            false
+        } else {
+            self.check_path(key, loc);
+            self.check_trigger(key, loc, hit)
+        };
+        if triggered {
+            self.injected += 1;
+            if let Some(ref mut locs) = self.injected_locs {
+                locs.push((self.ordinal, loc.clone()));
+            }
+        }
+        if let Some(ref mut rec) = self.recording {
+            rec.push(Decision::new(self.ordinal, loc, triggered));
+        }
+        triggered
+    }
+
+    // While replaying, returns the recorded decision for the failpoint
+    // at `loc`.  If the failpoint isn't the one recorded, warns and
+    // records the error for `stop_replay()`, and injects nothing more.
+    // It doesn't panic, since failpoints are also reached inside the
+    // allocator.
+    fn check_replay(&mut self, loc: &Location) -> bool {
+        if self.replay_error.is_some() {
+            return false;
+        }
+        let k = self.ordinal;
+        let replay = self.replay.as_deref().unwrap_or_default();
+        let msg = match replay.get(k as usize - 1) {
+            Some(d) if d.matches(loc) => return d.injected,
+            Some(d) => format!(
+                "replay diverged at ordinal {k}: expected {}, got {}",
+                d.format(),
+                loc.format()
+            ),
+            None => format!(
+                "replay diverged at ordinal {k}: expected the end of the schedule, got {}",
+                loc.format()
+            ),
+        };
+        self.warn(Verbosity::None, || msg.clone());
+        self.replay_error = Some(msg);
+        false
+    }
+
+    // In "Trigger" mode, records a divergence if, before any error has
+    // been injected, the failpoint at `loc` is not the one that was
+    // counted at the same ordinal.
+    fn check_path(&mut self, key: HitKey, loc: &Location) {
+        if self.injected > 0 || self.divergence.is_some() {
+            return;
+        }
+        let k = self.ordinal;
+        let expected = self.path.get(k as usize - 1).map(|&i| &self.path_locs[i]);
+        let msg = match expected {
+            Some((exp_key, _)) if *exp_key == key => return,
+            Some((_, exp)) => format!(
+                "path diverged at ordinal {k}: expected {}, got {}",
+                exp.format(),
+                loc.format()
+            ),
+            None => format!(
+                "path diverged at ordinal {k}: expected the end of the path, got {}",
+                loc.format()
+            ),
+        };
+        self.divergence = Some(msg);
+    }
+
+    // In "Trigger" mode, returns true if the `hit`th hit of the
+    // failpoint at `loc` should inject its error.
+    fn check_trigger(&mut self, key: HitKey, loc: &Location, hit: u64) -> bool {
+        if let Some((k, ref mut remaining)) = self.persisting {
+            if k == key && *remaining > 0 {
+                *remaining -= 1;
+                return true;
+            }
+        }
+
+        if let Some(ref d) = self.trigger_desc {
+            if loc.desc.as_deref() != Some(d.as_str()) {
+                return false;
             }
         }
 
+        let fire = if let Some(ref hits) = self.trigger_hits {
+            hits.contains(&hit)
+        } else if self.trigger_sequence.is_empty() {
+            self.trigger -= 1;
+            self.trigger == 0
+        } else {
+            self.trigger_sequence.contains(&self.ordinal)
+        };
+        if !fire {
+            return false;
+        }
+        if self.abort_on_trigger {
+            self.report_fault(loc, "an abort");
+            std::process::abort();
+        }
+
+        self.persisting = match self.persistence {
+            Persistence::Once => None,
+            Persistence::Always => Some((key, u64::MAX)),
+            Persistence::Hits(n) => Some((key, n)),
+        };
+        true
+    }
+
+    // Counts a hit of the failpoint at `loc`, whose key is `key`,
+    // returning its index in `hits` and the number of times it has
+    // been reached, including this one.
+    fn count_hit(&mut self, key: HitKey, loc: &Location) -> (usize, u64) {
+        let i = *self.hit_index.entry(key).or_insert_with(|| {
+            self.hits.push((loc.clone(), 0));
+            self.hits.len() - 1
+        });
+        self.hits[i].1 += 1;
+        (i, self.hits[i].1)
+    }
+
+    // Resets the ordinal, hit counts, injected errors and random
+    // choices, at the start of a count or trigger.
+    pub(crate) fn restart(&mut self) {
+        self.ordinal = 0;
+        self.hits.clear();
+        self.hit_index.clear();
+        self.persisting = None;
+        self.injected = 0;
+        self.divergence = None;
+        if let Some(ref mut locs) = self.injected_locs {
+            locs.clear();
+        }
+        self.inject_at = None;
+        if let Some(ref mut rec) = self.recording {
+            rec.clear();
+        }
+        self.replay = None;
+        self.replay_error = None;
+        self.rng = Rng::new(self.seed);
+    }
+
+    pub fn report_count(&mut self, loc: &Location) {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            target: "failpoint",
+            file = loc.file_name,
+            "crate" = loc.crate_name,
+            line = loc.line_no,
+            desc = loc.desc.as_deref(),
+            ordinal = self.ordinal,
+            mode = self.mode.name(),
+            "failpoint counted"
+        );
+
+        let ordinal = self.ordinal;
+        self.emit(Verbosity::Moderate, || FailpointEvent::Counted {
+            loc: loc.clone(),
+            ordinal,
+        });
+
         if self.verbosity >= Verbosity::Extreme {
             self.counted_locs.push(loc.clone());
         }
     }
 
     pub fn report_trigger(&mut self, loc: &Location, error: &dyn Debug) {
-        if self.verbosity >= Verbosity::Moderate {
-            if let Some(ref log) = self.logger {
-                let loc_str = loc.format();
-                let msg = format!("Triggered {loc_str} injecting Err({error:?})");
-                log(msg);
-            }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            target: "failpoint",
+            file = loc.file_name,
+            "crate" = loc.crate_name,
+            line = loc.line_no,
+            desc = loc.desc.as_deref(),
+            ordinal = self.ordinal,
+            mode = self.mode.name(),
+            error = ?error,
+            "failpoint triggered"
+        );
+
+        let ordinal = self.ordinal;
+        self.emit(Verbosity::Moderate, || FailpointEvent::Triggered {
+            loc: loc.clone(),
+            ordinal,
+            error: format!("{error:?}"),
+        });
+
+        if self.verbosity >= Verbosity::Extreme {
+            self.triggered_locs.push(loc.clone());
         }
+    }
+
+    /// Reports a triggered failpoint that injects something other
+    /// than an error, such as a short read.  `fault` describes what
+    /// was injected, for example "a short read of 2 bytes".
+    pub fn report_fault(&mut self, loc: &Location, fault: &str) {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            target: "failpoint",
+            file = loc.file_name,
+            "crate" = loc.crate_name,
+            line = loc.line_no,
+            desc = loc.desc.as_deref(),
+            ordinal = self.ordinal,
+            mode = self.mode.name(),
+            fault,
+            "failpoint triggered"
+        );
+
+        let ordinal = self.ordinal;
+        self.emit(Verbosity::Moderate, || FailpointEvent::Injected {
+            loc: loc.clone(),
+            ordinal,
+            fault: fault.to_string(),
+        });
+
         if self.verbosity >= Verbosity::Extreme {
             self.triggered_locs.push(loc.clone());
         }
     }
 
     pub fn report_unexpected_failure(&mut self, loc: &Location, error: &dyn Debug) {
-        if self.verbosity >= Verbosity::Moderate {
-            if let Some(ref log) = self.logger {
-                let loc_str = loc.format();
-                let msg = format!("Unexpected error in {loc_str} got Err({error:?})");
-                log(msg);
-            }
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            target: "failpoint",
+            file = loc.file_name,
+            "crate" = loc.crate_name,
+            line = loc.line_no,
+            desc = loc.desc.as_deref(),
+            ordinal = self.ordinal,
+            mode = self.mode.name(),
+            error = ?error,
+            "unexpected failure at failpoint"
+        );
+
+        let ordinal = self.ordinal;
+        self.emit(Verbosity::Moderate, || FailpointEvent::Unexpected {
+            loc: loc.clone(),
+            ordinal,
+            error: format!("{error:?}"),
+        });
+    }
+
+    /// Sends the message built by `msg` to the logger and any log
+    /// taps, if the verbosity is at least `level`.  The message is
+    /// only built if there is somewhere to send it.
+    pub fn log(&mut self, level: Verbosity, msg: impl FnOnce() -> String) {
+        self.emit(level, || FailpointEvent::Message {
+            level,
+            failure: false,
+            text: msg(),
+        });
+    }
+
+    /// Like [`Inner::log()`], but the message reports a failure.
+    pub fn warn(&mut self, level: Verbosity, msg: impl FnOnce() -> String) {
+        self.emit(level, || FailpointEvent::Message {
+            level,
+            failure: true,
+            text: msg(),
+        });
+    }
+
+    /// Sends the event built by `event` to the logger and any log
+    /// taps, if the verbosity is at least `level`.  The event is only
+    /// built if there is somewhere to send it.
+    pub fn emit(&mut self, level: Verbosity, event: impl FnOnce() -> FailpointEvent) {
+        if self.verbosity < level {
+            return;
+        }
+
+        // A session logs through the state it was started from.
+        if let Some(parent) = self.parent {
+            let event = event();
+            let mut g = parent.mu.lock().unwrap_or_else(|e| e.into_inner());
+            g.emit_event(level, event);
+            return;
         }
+
+        // Without a logger of our own, fall back to the `log` crate.
+        let to_log = cfg!(feature = "log") && self.logger.is_none();
+        if self.logger.is_none() && self.log_taps.is_empty() && !to_log {
+            return;
+        }
+
+        let event = event();
+        if !self.log_taps.is_empty() {
+            let msg = event.to_string();
+            // Drop any taps whose receiver has gone away.
+            self.log_taps.retain(|tap| tap.send(msg.clone()).is_ok());
+        }
+        if let Some(ref log_fn) = self.logger {
+            log_fn(&event);
+        } else {
+            #[cfg(feature = "log")]
+            log::log!(target: "failpoint", log_level(&event), "{event}");
+        }
+    }
+
+    // Emits an event that has already been built.  Not generic, so
+    // that a session's `emit()` can call it for its parent.
+    fn emit_event(&mut self, level: Verbosity, event: FailpointEvent) {
+        self.emit(level, || event);
+    }
+}
+
+// Maps an event to a `log` level.
+#[cfg(all(feature = "failpoint_enabled", feature = "log"))]
+fn log_level(event: &FailpointEvent) -> log::Level {
+    if event.is_failure() {
+        log::Level::Warn
+    } else if event.verbosity() <= Verbosity::Moderate {
+        log::Level::Debug
+    } else {
+        log::Level::Trace
     }
 }
 
@@ -168,20 +646,227 @@ impl Default for State {
     }
 }
 
+#[cfg(feature = "failpoint_enabled")]
+thread_local! {
+    // The state of the session this thread is in, if any.
+    static SESSION: Cell<Option<&'static State>> = const { Cell::new(None) };
+}
+
+// The states of sessions that have ended, to be reused, since they are
+// leaked so that they can be locked like the global state.
+#[cfg(feature = "failpoint_enabled")]
+static FREE_SESSIONS: Mutex<Vec<&'static State>> = Mutex::new(Vec::new());
+
 // See HIDDEN DOC above.
+//
+// Returns the state of this thread's session, or the global state.
 #[cfg(feature = "failpoint_enabled")]
 #[doc(hidden)]
 pub fn get_state() -> &'static State {
-    &STATE
+    SESSION
+        .try_with(|s| s.get())
+        .ok()
+        .flatten()
+        .unwrap_or(&STATE)
+}
+
+/// An isolated failpoint session for the current thread.
+///
+/// While a `Session` is alive, the failpoints reached on the thread
+/// that made it, and the functions that count and trigger them, use
+/// the session's own state instead of the global state.  So several
+/// threads, each in its own session, can count and trigger failpoints
+/// at the same time without interfering.  This is how
+/// [`test_codepath!`](crate::test_codepath!) runs trigger iterations
+/// in parallel.
+///
+/// A new session starts with the verbosity, seed, persistence, active
+/// flag and last counted path of the state it was made from, and logs
+/// through it.
+/// Threads spawned in a session do not join it, they use the global
+/// state.
+///
+/// A session's state is allocated the first time it is needed and
+/// never freed.  When a session is dropped its state is kept, and
+/// reused by the next session made on any thread, so a process holds
+/// as many states as the most sessions it had at once.
+///
+/// Nested sessions must be dropped in the reverse order they were
+/// made, since dropping one makes the state that was in use when it
+/// was made current again.
+///
+/// # Examples
+///
+/// ```rust
+/// use failpoint::{Session, failpoint};
+///
+/// fn step() -> Result<(), String> {
+///     let res = Ok(());
+///     failpoint!(res, "failed".to_string())
+/// }
+///
+/// failpoint::start_counter();
+/// let t = std::thread::spawn(|| {
+///     let _session = Session::new();
+///     failpoint::start_trigger(1);
+///     step()
+/// });
+/// assert!(t.join().unwrap().is_err());
+///
+/// // The session didn't change the global state.
+/// step().unwrap();
+/// assert_eq!(failpoint::get_count(), 1);
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+pub struct Session {
+    state: &'static State,
+    prev: Option<&'static State>,
+
+    // A session belongs to the thread that made it.
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+#[cfg(feature = "failpoint_enabled")]
+impl Session {
+    /// Starts a session on this thread, which lasts until the
+    /// `Session` is dropped.  Sessions may be nested.
+    pub fn new() -> Self {
+        let state = FREE_SESSIONS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop()
+            .unwrap_or_else(|| &*Box::leak(Box::<State>::default()));
+
+        {
+            let parent = lock_state();
+            let mut g = state.mu.lock().unwrap_or_else(|e| e.into_inner());
+            *g = Inner {
+                active: parent.active,
+                verbosity: parent.verbosity,
+                seed: parent.seed,
+                rng: Rng::new(parent.seed),
+                persistence: parent.persistence,
+                path: parent.path.clone(),
+                path_locs: parent.path_locs.clone(),
+                injected_locs: parent.injected_locs.as_ref().map(|_| Vec::new()),
+                parent: Some(get_state()),
+                ..Inner::default()
+            };
+        }
+
+        let prev = SESSION.with(|s| s.replace(Some(state)));
+        Self {
+            state,
+            prev,
+            _not_send: std::marker::PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "failpoint_enabled")]
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "failpoint_enabled")]
+impl Drop for Session {
+    fn drop(&mut self) {
+        _ = SESSION.try_with(|s| {
+            debug_assert!(
+                s.get().is_some_and(|cur| std::ptr::eq(cur, self.state)),
+                "sessions must be dropped in the reverse order they were made"
+            );
+            s.set(self.prev)
+        });
+        FREE_SESSIONS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(self.state);
+    }
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+pub struct Session {}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+impl Session {
+    #[inline]
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "failpoint_enabled")]
+thread_local! {
+    // True while this thread holds the state lock.  Const initialized
+    // so that it can be read from inside the allocator.
+    static HOLDS_LOCK: Cell<bool> = const { Cell::new(false) };
 }
 
 // See HIDDEN DOC above.
+//
+// The locked state.  Records that this thread holds the lock, so
+// that the allocator failpoints don't try to take it again when the
+// state allocates.  When dropped after a failpoint was reached, the
+// thread yields to the interleaving explorer once the lock is
+// released.
 #[cfg(feature = "failpoint_enabled")]
 #[doc(hidden)]
-pub fn lock_state<'a>() -> MutexGuard<'a, Inner> {
+pub struct StateGuard<'a> {
+    g: ManuallyDrop<MutexGuard<'a, Inner>>,
+}
+
+#[cfg(feature = "failpoint_enabled")]
+impl Deref for StateGuard<'_> {
+    type Target = Inner;
+
+    fn deref(&self) -> &Inner {
+        &self.g
+    }
+}
+
+#[cfg(feature = "failpoint_enabled")]
+impl DerefMut for StateGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Inner {
+        &mut self.g
+    }
+}
+
+#[cfg(feature = "failpoint_enabled")]
+impl Drop for StateGuard<'_> {
+    fn drop(&mut self) {
+        // SAFETY: `g` is not used again.
+        unsafe { ManuallyDrop::drop(&mut self.g) };
+        _ = HOLDS_LOCK.try_with(|h| h.set(false));
+        interleave::yield_if_pending();
+    }
+}
+
+// See HIDDEN DOC above.
+#[cfg(feature = "failpoint_enabled")]
+#[doc(hidden)]
+pub fn lock_state<'a>() -> StateGuard<'a> {
     let state = get_state();
     let g = state.mu.lock().unwrap();
-    g
+    _ = HOLDS_LOCK.try_with(|h| h.set(true));
+    StateGuard {
+        g: ManuallyDrop::new(g),
+    }
+}
+
+// Returns true if this thread holds the state lock.
+#[cfg(feature = "failpoint_enabled")]
+pub(crate) fn holds_state_lock() -> bool {
+    HOLDS_LOCK.try_with(|h| h.get()).unwrap_or(true)
 }
 
 #[cfg(feature = "failpoint_enabled")]
@@ -257,8 +942,11 @@ pub fn start_counter() {
     let mut g = lock_state();
     g.mode = Mode::Count;
     g.counter = 0;
+    g.path = Vec::new();
+    g.path_locs = Vec::new();
     g.counted_locs = Vec::new();
     g.triggered_locs = Vec::new();
+    g.restart();
 }
 
 #[cfg(not(feature = "failpoint_enabled"))]
@@ -292,12 +980,152 @@ pub fn start_trigger(trigger_after: i64) {
     let mut g = lock_state();
     g.mode = Mode::Trigger;
     g.trigger = trigger_after;
+    g.trigger_desc = None;
+    g.trigger_hits = None;
+    g.trigger_sequence = Vec::new();
+    g.restart();
 }
 
 #[cfg(not(feature = "failpoint_enabled"))]
 #[inline]
 pub fn start_trigger(_trigger_after: i64) {}
 
+/// Enters trigger mode and arms the first failpoint whose
+/// description is `desc`.
+///
+/// Failpoints with any other description (or none) are ignored
+/// while this trigger is armed.
+///
+/// # Examples
+///
+/// ```rust
+/// use failpoint::failpoint;
+/// use anyhow::Error;
+///
+/// fn do_something() -> Result<(), Error> {
+///     Ok(())
+/// }
+///
+/// failpoint::start_trigger_named("second");
+/// let result = do_something();
+/// let result = failpoint!(result, Error::msg("Error 1"), "first");
+/// assert!(result.is_ok());
+/// let result = failpoint!(result, Error::msg("Error 2"), "second");
+/// assert!(result.is_err());
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+pub fn start_trigger_named(desc: &str) {
+    let mut g = lock_state();
+    g.mode = Mode::Trigger;
+    g.trigger = 1;
+    g.trigger_desc = Some(desc.to_string());
+    g.trigger_hits = None;
+    g.trigger_sequence = Vec::new();
+    g.restart();
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+pub fn start_trigger_named(_desc: &str) {}
+
+/// Enters trigger mode and arms the `hit`th time a failpoint whose
+/// description is `desc` is reached, counting from 1.
+///
+/// Each failpoint location and description is counted separately, so
+/// a failpoint in a loop can be made to fail on a later iteration.  See
+/// [`start_trigger_hits()`].
+#[cfg(feature = "failpoint_enabled")]
+pub fn start_trigger_hit(desc: &str, hit: u64) {
+    start_trigger_hits(desc, hit..=hit);
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+pub fn start_trigger_hit(_desc: &str, _hit: u64) {}
+
+/// Enters trigger mode and arms every hit in `hits` of a failpoint
+/// whose description is `desc`, counting from 1.
+///
+/// Each failpoint location has its own hit counter for each
+/// description reached there, so the paths written by one
+/// [`fs::File::write()`](crate::fs::File) call site are counted
+/// separately.  The counters restart when [`start_counter()`] or one
+/// of the `start_trigger` functions is called.  Failpoints with any
+/// other description are ignored.
+///
+/// # Examples
+///
+/// ```rust
+/// use failpoint::failpoint;
+///
+/// fn write_batch(items: &[i32]) -> Vec<Result<i32, String>> {
+///     items
+///         .iter()
+///         .map(|&i| {
+///             let res = Ok(i);
+///             failpoint!(res, format!("item {i} failed"), "write item")
+///         })
+///         .collect()
+/// }
+///
+/// failpoint::start_trigger_hits("write item", 2..=3);
+/// let res = write_batch(&[1, 2, 3, 4]);
+/// assert!(res[0].is_ok());
+/// assert!(res[1].is_err() && res[2].is_err());
+/// assert!(res[3].is_ok());
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+pub fn start_trigger_hits(desc: &str, hits: RangeInclusive<u64>) {
+    let mut g = lock_state();
+    g.mode = Mode::Trigger;
+    g.trigger = 1;
+    g.trigger_desc = Some(desc.to_string());
+    g.trigger_hits = Some(hits);
+    g.trigger_sequence = Vec::new();
+    g.restart();
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+pub fn start_trigger_hits(_desc: &str, _hits: RangeInclusive<u64>) {}
+
+/// Enters trigger mode and arms every failpoint whose ordinal is in
+/// `ordinals`, so that one run can inject an error and then another
+/// on the error handling path.
+///
+/// # Examples
+///
+/// ```rust
+/// use failpoint::failpoint;
+///
+/// fn save() -> Result<(), String> {
+///     let res = Ok(());
+///     let res = failpoint!(res, "write failed".to_string(), "write");
+///     if res.is_err() {
+///         let undo = Ok(());
+///         failpoint!(undo, "rollback failed".to_string(), "rollback")?;
+///     }
+///     res
+/// }
+///
+/// failpoint::start_trigger_sequence(&[1, 2]);
+/// assert_eq!(save(), Err("rollback failed".to_string()));
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+pub fn start_trigger_sequence(ordinals: &[i64]) {
+    let mut g = lock_state();
+    g.mode = Mode::Trigger;
+    g.trigger = i64::MAX;
+    g.trigger_desc = None;
+    g.trigger_hits = None;
+    g.trigger_sequence = ordinals.to_vec();
+    g.restart();
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+pub fn start_trigger_sequence(_ordinals: &[i64]) {}
+
 /// Returns the current count of failpoints encountered in count mode.
 ///
 /// This function returns the number of failpoints that have been encountered
@@ -365,6 +1193,200 @@ pub fn get_triggered_locs() -> Vec<Location> {
     Vec::new()
 }
 
+/// Get the number of times each failpoint has been reached since the
+/// last call to `start_counter()` or `start_trigger()`, in the order
+/// they were first reached.  A location reached with different
+/// descriptions has a count for each.
+#[cfg(feature = "failpoint_enabled")]
+pub fn get_hit_counts() -> Vec<(Location, u64)> {
+    let g = lock_state();
+    g.hits.clone()
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+pub fn get_hit_counts() -> Vec<(Location, u64)> {
+    Vec::new()
+}
+
+/// Sets what a failpoint does after it has been triggered, from the
+/// next call to `start_trigger()` on.
+///
+/// By default a triggered failpoint fails once, and later hits of it
+/// pass through.  To test that retries give up, a failpoint can keep
+/// failing on every later hit, or on the next `n` hits.  Other
+/// failpoints are not affected, including those reached at the same
+/// location with a different description, such as writes to other
+/// paths through [`fs::File`](crate::fs::File).  This applies however
+/// the failpoint was triggered, by ordinal, description or hit.
+///
+/// # Examples
+///
+/// ```rust
+/// use failpoint::{Persistence, failpoint};
+///
+/// fn fetch(attempts: u32) -> Result<u32, String> {
+///     let mut last = Err("no attempts".to_string());
+///     for a in 1..=attempts {
+///         let res = Ok(a);
+///         last = failpoint!(res, "unavailable".to_string(), "fetch");
+///         if last.is_ok() {
+///             break;
+///         }
+///     }
+///     last
+/// }
+///
+/// failpoint::set_persistence(Persistence::Hits(1));
+/// failpoint::start_trigger(1);
+/// assert_eq!(fetch(3), Ok(3));
+///
+/// failpoint::set_persistence(Persistence::Always);
+/// failpoint::start_trigger(1);
+/// assert!(fetch(3).is_err());
+/// assert_eq!(failpoint::get_injected_count(), 3);
+/// # failpoint::set_persistence(Persistence::Once);
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+pub fn set_persistence(p: Persistence) {
+    let mut g = lock_state();
+    g.persistence = p;
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+pub fn set_persistence(_p: Persistence) {}
+
+/// Returns the number of errors injected since the last call to
+/// `start_trigger()`.
+#[cfg(feature = "failpoint_enabled")]
+pub fn get_injected_count() -> i64 {
+    let g = lock_state();
+    g.injected
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+pub fn get_injected_count() -> i64 {
+    0
+}
+
+/// Returns the number of failpoints reached since the last call to
+/// `start_counter()` or `start_trigger()`, whether or not they were
+/// counted or injected an error.
+#[cfg(feature = "failpoint_enabled")]
+pub fn get_reached_count() -> i64 {
+    let g = lock_state();
+    g.ordinal
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+pub fn get_reached_count() -> i64 {
+    0
+}
+
+// See HIDDEN DOC above.
+//
+// Starts or stops collecting the failpoints that inject errors, and
+// returns whether they were being collected.
+#[cfg(feature = "failpoint_enabled")]
+#[doc(hidden)]
+pub fn collect_injected_locs(on: bool) -> bool {
+    let mut g = lock_state();
+    let was = g.injected_locs.is_some();
+    if on != was {
+        g.injected_locs = on.then(Vec::new);
+    }
+    was
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[doc(hidden)]
+pub fn collect_injected_locs(_on: bool) -> bool {
+    false
+}
+
+// See HIDDEN DOC above.
+//
+// Returns the ordinals and locations of the failpoints that injected
+// errors since the start of the run, while they are collected.
+#[cfg(feature = "failpoint_enabled")]
+#[doc(hidden)]
+pub fn get_injected_locs() -> Vec<(i64, Location)> {
+    let g = lock_state();
+    g.injected_locs.clone().unwrap_or_default()
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[doc(hidden)]
+pub fn get_injected_locs() -> Vec<(i64, Location)> {
+    Vec::new()
+}
+
+/// Returns how the failpoints reached since the last call to
+/// `start_trigger()` first differed from those counted since the last
+/// call to `start_counter()`, if they did.
+///
+/// Only the failpoints reached before an error was injected are
+/// compared, since the path is expected to change after that.  A
+/// divergence means the code path isn't deterministic, for example
+/// because it iterates over a hash map, so the ordinal of a trigger
+/// doesn't pick the failpoint that was counted at that ordinal.
+///
+/// # Examples
+///
+/// ```rust
+/// use failpoint::failpoint;
+///
+/// fn step(first: bool) -> Result<(), String> {
+///     let res = Ok(());
+///     if first {
+///         failpoint!(res, "a failed".to_string(), "a")
+///     } else {
+///         failpoint!(res, "b failed".to_string(), "b")
+///     }
+/// }
+///
+/// failpoint::start_counter();
+/// _ = step(true);
+/// failpoint::start_trigger(2);
+/// _ = step(false);
+/// let d = failpoint::get_divergence().unwrap();
+/// assert!(d.starts_with("path diverged at ordinal 1: expected Failpoint \"a\""));
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+pub fn get_divergence() -> Option<String> {
+    let g = lock_state();
+    g.divergence.clone()
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+pub fn get_divergence() -> Option<String> {
+    None
+}
+
+/// Sets the seed used to choose random faults, such as
+/// [`TornWrite::RandomPrefix`](crate::io::TornWrite::RandomPrefix).
+///
+/// The random choices restart from the seed each time
+/// [`start_counter()`], [`start_trigger()`] or
+/// [`start_trigger_named()`] is called, so a run can be reproduced by
+/// setting the same seed.  The seed is 0 until this is called.
+///
+/// # Examples
+///
+/// ```rust
+/// failpoint::set_seed(42);
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+pub fn set_seed(seed: u64) {
+    let mut g = lock_state();
+    g.seed = seed;
+    g.rng = Rng::new(seed);
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+pub fn set_seed(_seed: u64) {}
+
 /// Sets the verbosity level for logging output.
 ///
 /// Controls how much logging output is generated by the failpoint
@@ -396,6 +1418,17 @@ pub fn set_verbosity(_v: i32) {}
 /// failpoints are triggered (in trigger mode) or when using the test_codepath
 /// macro. Set to `None` to disable logging.
 ///
+/// The logger receives each [`FailpointEvent`] formatted as a string.
+/// Use [`set_event_logger()`] to receive the events themselves.
+/// Setting a logger replaces any event logger, and vice versa.
+///
+/// With the `log` feature enabled, messages are sent to the `log`
+/// crate with the target `failpoint` when no logger is set.  Counted
+/// and triggered failpoints are logged at the `Debug` level, the
+/// extra detail produced at `Verbosity::Extreme` at the `Trace`
+/// level, and unexpected failures at the `Warn` level.  The verbosity
+/// still controls which messages are produced.
+///
 /// # Examples
 ///
 /// ```rust
@@ -410,26 +1443,93 @@ pub fn set_verbosity(_v: i32) {}
 /// ```
 #[cfg(feature = "failpoint_enabled")]
 pub fn set_logger(l: Option<Logger>) {
+    set_event_logger(l.map(|l| -> EventLogger { Box::new(move |e| l(e.to_string())) }));
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+pub fn set_logger(_l: Option<Logger>) {}
+
+/// Sets a function that receives each [`FailpointEvent`].
+///
+/// Events are subject to the verbosity in the same way as the
+/// messages passed to a logger set with [`set_logger()`].  Setting an
+/// event logger replaces any logger, and vice versa.  Set to `None`
+/// to disable logging.
+///
+/// # Examples
+///
+/// ```rust
+/// use failpoint::FailpointEvent;
+///
+/// failpoint::set_verbosity(failpoint::Verbosity::Moderate);
+/// failpoint::set_event_logger(Some(Box::new(|e| {
+///     if let FailpointEvent::Triggered { loc, ordinal, .. } = e {
+///         println!("triggered #{ordinal} at {}:{}", loc.file_name, loc.line_no);
+///     }
+/// })));
+/// # failpoint::set_event_logger(None);
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+pub fn set_event_logger(l: Option<EventLogger>) {
     let mut g = lock_state();
     g.logger = l;
 }
 
 #[cfg(not(feature = "failpoint_enabled"))]
 #[inline]
-pub fn set_logger(_l: Option<Logger>) {}
+pub fn set_event_logger(_l: Option<EventLogger>) {}
 
 // See HIDDEN DOC above.
 #[cfg(feature = "failpoint_enabled")]
 #[doc(hidden)]
 pub fn log_if_verbose(level: Verbosity, msg: String) {
-    let g = lock_state();
-    if g.verbosity >= level {
-        if let Some(ref log_fn) = g.logger {
-            log_fn(msg);
-        }
-    }
+    let mut g = lock_state();
+    g.log(level, || msg);
 }
 
 #[cfg(not(feature = "failpoint_enabled"))]
 #[doc(hidden)]
 pub fn log_if_verbose(_level: Verbosity, _msg: String) {}
+
+// See HIDDEN DOC above.
+#[cfg(feature = "failpoint_enabled")]
+#[doc(hidden)]
+pub fn warn_if_verbose(level: Verbosity, msg: String) {
+    let mut g = lock_state();
+    g.warn(level, || msg);
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[doc(hidden)]
+pub fn warn_if_verbose(_level: Verbosity, _msg: String) {}
+
+// See HIDDEN DOC above.
+#[cfg(feature = "failpoint_enabled")]
+#[doc(hidden)]
+pub fn log_event(event: FailpointEvent) {
+    let mut g = lock_state();
+    g.emit(event.verbosity(), || event);
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[doc(hidden)]
+pub fn log_event(_event: crate::FailpointEvent) {}
+
+// See HIDDEN DOC above.
+//
+// Applies the mutation passed to `failpoint_corrupt!`.  Passing the
+// closure to a function lets the compiler infer its argument type.
+#[doc(hidden)]
+pub fn corrupt_with<T, F: FnOnce(&mut T)>(v: &mut T, mutate: F) {
+    mutate(v)
+}
+
+/// Registers a channel that receives a copy of every log message, as
+/// well as the logger.  The tap is removed once the receiver is
+/// dropped.  Used by the control server's `tail` command.
+#[cfg(feature = "control")]
+pub(crate) fn add_log_tap(tap: Sender<String>) {
+    let mut g = lock_state();
+    g.log_taps.push(tap);
+}
@@ -0,0 +1,283 @@
+//! Filesystem operations with failpoints.
+//!
+//! This module mirrors the commonly used parts of [`std::fs`].  Every
+//! operation is a failpoint whose description names the operation and
+//! the path, for example `fs::rename /data/wal.tmp to /data/wal`, so
+//! [`test_codepath!`](crate::test_codepath!) can enumerate, and fail,
+//! every filesystem operation on a code path.
+//!
+//! When one of these failpoints is triggered the operation is not
+//! performed and an [`io::Error`] of kind [`io::ErrorKind::Other`] is
+//! returned instead.  A [`File`] can inject other faults, such as torn
+//! writes, see [`File::with_fault()`].
+//!
+//! Code can switch between this module and `std::fs` in test builds:
+//!
+//! ```rust
+//! #[cfg(test)]
+//! use failpoint::fs;
+//! #[cfg(not(test))]
+//! use std::fs;
+//! ```
+//!
+//! For example:
+//!
+//! ```rust
+//! use std::io::{self, Write};
+//! use std::path::Path;
+//!
+//! use failpoint::{fs, test_codepath};
+//!
+//! // Atomically replace the contents of `path`.
+//! fn save(path: &Path, data: &[u8]) -> io::Result<()> {
+//!     let tmp = path.with_extension("tmp");
+//!     let mut f = fs::File::create(&tmp)?;
+//!     f.write_all(data)?;
+//!     f.sync_all()?;
+//!     fs::rename(&tmp, path)
+//! }
+//!
+//! let dir = std::env::temp_dir().join(format!("failpoint-fs-doc-{}", std::process::id()));
+//! std::fs::create_dir_all(&dir).unwrap();
+//! let path = dir.join("data");
+//!
+//! let res = test_codepath! {
+//!     codepath {
+//!         save(&path, b"hello")
+//!     }
+//! };
+//!
+//! // create, write, sync_all and rename.
+//! assert!(res.success());
+//! assert_eq!(res.expected_trigger_count, 4);
+//! # std::fs::remove_dir_all(&dir).unwrap();
+//! ```
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::Location;
+#[cfg(feature = "failpoint_enabled")]
+use crate::io::location;
+use crate::io::{IoFault, Transfer, reach, reach_write};
+
+// Makes the `Location` of a failpoint whose description names a path,
+// from `format!` arguments.  Formatting the path allocates, so it is
+// only done while failpoints are active, and otherwise there is no
+// location and no failpoint.
+#[cfg(feature = "failpoint_enabled")]
+macro_rules! path_location {
+    ($($arg: tt)+) => {
+        if crate::is_active() {
+            Some(location!(format!($($arg)+)))
+        } else {
+            None
+        }
+    };
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+macro_rules! path_location {
+    ($($arg: tt)+) => {
+        None
+    };
+}
+
+/// A [`std::fs::File`] whose operations are failpoints.
+///
+/// `read()`, `write()`, `flush()`, `seek()` and `sync_all()` are
+/// failpoints described as `File::read PATH`, `File::write PATH` and
+/// so on.
+#[derive(Debug)]
+pub struct File {
+    inner: fs::File,
+    path: PathBuf,
+    fault: IoFault,
+}
+
+impl File {
+    /// Opens a file in read-only mode, see [`std::fs::File::open()`].
+    /// A failpoint described as `File::open PATH`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<File> {
+        let path = path.as_ref();
+        fail(path_location!("File::open {}", path.display()))?;
+        Ok(File {
+            inner: fs::File::open(path)?,
+            path: path.to_path_buf(),
+            fault: IoFault::default(),
+        })
+    }
+
+    /// Opens a file in write-only mode, creating or truncating it,
+    /// see [`std::fs::File::create()`].  A failpoint described as
+    /// `File::create PATH`.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<File> {
+        let path = path.as_ref();
+        fail(path_location!("File::create {}", path.display()))?;
+        Ok(File {
+            inner: fs::File::create(path)?,
+            path: path.to_path_buf(),
+            fault: IoFault::default(),
+        })
+    }
+
+    /// Syncs the file's content and metadata to disk, see
+    /// [`std::fs::File::sync_all()`].  A failpoint described as
+    /// `File::sync_all PATH`.
+    pub fn sync_all(&self) -> io::Result<()> {
+        let loc = path_location!("File::sync_all {}", self.path.display());
+        reach_at(loc, self.fault, 0)?;
+        self.inner.sync_all()
+    }
+
+    /// Sets what the failpoints of this file inject when triggered.
+    /// `open()` and `create()` always return an error of kind
+    /// [`io::ErrorKind::Other`].
+    ///
+    /// For example, to test recovery from a crash part way through
+    /// appending to a log:
+    ///
+    /// ```rust,no_run
+    /// use failpoint::fs::File;
+    /// use failpoint::io::{IoFault, TornWrite};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let wal = File::create("/tmp/wal")?.with_fault(IoFault::Torn(TornWrite::RandomPrefix));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_fault(mut self, fault: IoFault) -> Self {
+        self.fault = fault;
+        self
+    }
+
+    /// Makes triggered failpoints return an error of kind `kind`.
+    pub fn with_error_kind(self, kind: io::ErrorKind) -> Self {
+        self.with_fault(IoFault::Error(kind))
+    }
+
+    /// The path the file was opened with.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get_ref(&self) -> &fs::File {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut fs::File {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> fs::File {
+        self.inner
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let loc = path_location!("File::read {}", self.path.display());
+        let len = reach_at(loc, self.fault, buf.len())?;
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(loc) = path_location!("File::write {}", self.path.display()) else {
+            return self.inner.write(buf);
+        };
+        let inner = &self.inner;
+        let file_len = || Ok(Some(inner.metadata()?.len()));
+        match reach_write(&loc, self.fault, buf.len(), file_len)? {
+            Transfer::Bytes(len) => self.inner.write(&buf[..len]),
+            Transfer::Torn {
+                keep,
+                truncate,
+                err,
+            } => {
+                self.inner.write_all(&buf[..keep])?;
+                if let Some(len) = truncate {
+                    self.inner.set_len(len)?;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let loc = path_location!("File::flush {}", self.path.display());
+        reach_at(loc, self.fault, 0)?;
+        self.inner.flush()
+    }
+}
+
+impl Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let loc = path_location!("File::seek {}", self.path.display());
+        reach_at(loc, self.fault, 0)?;
+        self.inner.seek(pos)
+    }
+}
+
+/// Renames a file or directory, see [`std::fs::rename()`].  A
+/// failpoint described as `fs::rename FROM to TO`.
+pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    fail(path_location!(
+        "fs::rename {} to {}",
+        from.display(),
+        to.display()
+    ))?;
+    fs::rename(from, to)
+}
+
+/// Removes a file, see [`std::fs::remove_file()`].  A failpoint
+/// described as `fs::remove_file PATH`.
+pub fn remove_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    fail(path_location!("fs::remove_file {}", path.display()))?;
+    fs::remove_file(path)
+}
+
+/// Creates a directory and all of its missing parents, see
+/// [`std::fs::create_dir_all()`].  A failpoint described as
+/// `fs::create_dir_all PATH`.
+pub fn create_dir_all<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    fail(path_location!("fs::create_dir_all {}", path.display()))?;
+    fs::create_dir_all(path)
+}
+
+/// Reads the whole of a file into a string, see
+/// [`std::fs::read_to_string()`].  A failpoint described as
+/// `fs::read_to_string PATH`.
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let path = path.as_ref();
+    fail(path_location!("fs::read_to_string {}", path.display()))?;
+    fs::read_to_string(path)
+}
+
+/// Writes a whole file, see [`std::fs::write()`].  A failpoint
+/// described as `fs::write PATH`.
+pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> io::Result<()> {
+    let path = path.as_ref();
+    fail(path_location!("fs::write {}", path.display()))?;
+    fs::write(path, contents)
+}
+
+// Reaches the failpoint at `loc`, if there is one, for an operation
+// on `len` bytes.  See `path_location!`.
+fn reach_at(loc: Option<Location>, fault: IoFault, len: usize) -> io::Result<usize> {
+    match loc {
+        Some(loc) => reach(&loc, fault, len),
+        None => Ok(len),
+    }
+}
+
+// Reaches the failpoint at `loc`, if there is one, returning an error
+// if it was triggered.
+fn fail(loc: Option<Location>) -> io::Result<()> {
+    reach_at(loc, IoFault::default(), 0).map(|_| ())
+}
@@ -0,0 +1,470 @@
+//! Exploring thread interleavings.
+//!
+//! An [`Explorer`] runs a test body that spawns threads with
+//! [`Scope::spawn()`].  Only one of those threads runs at a time, and
+//! every failpoint one of them reaches is a yield point, where the
+//! explorer chooses which thread runs next.  The body is run again for
+//! each schedule of choices, much as
+//! [`test_codepath!`](crate::test_codepath!) runs a code path for each
+//! failpoint ordinal, until a schedule makes the body panic or every
+//! schedule has been run.
+//!
+//! Switching away from a thread that could have continued is a
+//! preemption.  The number of schedules grows quickly with the number
+//! of yield points, so only schedules with at most
+//! [`Explorer::max_preemptions()`] preemptions are run.  Most
+//! concurrency bugs need only one or two.
+//!
+//! Failpoints are counted while the schedules are run, so they don't
+//! inject errors.
+//!
+//! ```rust
+//! use std::sync::Arc;
+//! use std::sync::atomic::{AtomicI32, Ordering};
+//!
+//! use failpoint::failpoint;
+//! use failpoint::interleave::Explorer;
+//!
+//! // Increments the counter with a load and a store, which isn't
+//! // atomic.
+//! fn increment(counter: &AtomicI32) -> Result<(), String> {
+//!     let n = counter.load(Ordering::SeqCst);
+//!     let res: Result<(), String> = Ok(());
+//!     failpoint!(res, "error".to_string(), "increment")?;
+//!     counter.store(n + 1, Ordering::SeqCst);
+//!     Ok(())
+//! }
+//!
+//! let res = Explorer::new().run(|s| {
+//!     let counter = Arc::new(AtomicI32::new(0));
+//!     for _ in 0..2 {
+//!         let c = counter.clone();
+//!         s.spawn(move || increment(&c).unwrap());
+//!     }
+//!     s.join();
+//!     assert_eq!(counter.load(Ordering::SeqCst), 2);
+//! });
+//!
+//! // Running the second thread between the load and the store of the
+//! // first loses an increment.
+//! let failure = res.failure.unwrap();
+//! assert_eq!(failure.schedule, vec![0, 1, 1]);
+//! ```
+//!
+//! The explorer can't see threads blocking on each other, so the
+//! threads must not reach a failpoint while holding a lock another
+//! thread needs, or wait for another thread outside a failpoint.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "failpoint_enabled")]
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+#[cfg(feature = "failpoint_enabled")]
+use crate::{Verbosity, log_if_verbose, start_counter};
+
+#[cfg(feature = "failpoint_enabled")]
+thread_local! {
+    // The scheduler of a thread spawned by `Scope::spawn()`, and its
+    // id.
+    static CURRENT: RefCell<Option<(Arc<Sched>, usize)>> = const { RefCell::new(None) };
+
+    // Set when this thread reaches a failpoint, so that it yields once
+    // the state lock is released.  Const initialized so that it can be
+    // used from inside the allocator.
+    static YIELD_PENDING: Cell<bool> = const { Cell::new(false) };
+
+    // True while this thread is yielding, so that failpoints reached
+    // by the scheduler itself don't yield again.
+    static YIELDING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Records that this thread has reached a failpoint.
+#[cfg(feature = "failpoint_enabled")]
+pub(crate) fn note_yield_point() {
+    _ = YIELD_PENDING.try_with(|p| p.set(true));
+}
+
+// Yields to the scheduler if this thread has reached a failpoint since
+// it last yielded.  Must be called without the state lock held.
+#[cfg(feature = "failpoint_enabled")]
+pub(crate) fn yield_if_pending() {
+    if !YIELD_PENDING
+        .try_with(|p| p.replace(false))
+        .unwrap_or(false)
+    {
+        return;
+    }
+    if YIELDING.try_with(|y| y.replace(true)).unwrap_or(true) {
+        return;
+    }
+    let current = CURRENT.try_with(|c| c.borrow().clone()).ok().flatten();
+    if let Some((sched, id)) = current {
+        sched.yield_now(id);
+    }
+    _ = YIELDING.try_with(|y| y.set(false));
+}
+
+// A choice between threads made by the scheduler.
+#[cfg(feature = "failpoint_enabled")]
+#[derive(Debug, Clone)]
+struct Decision {
+    // The threads that could run, starting with the one that yielded,
+    // if it did.
+    candidates: Vec<usize>,
+
+    // The index of the chosen thread in `candidates`.
+    chosen: usize,
+
+    // True if the running thread yielded, rather than finished.
+    yielded: bool,
+
+    // The number of preemptions before this decision.
+    preemptions: usize,
+}
+
+#[cfg(feature = "failpoint_enabled")]
+#[derive(Debug, Default)]
+struct SchedState {
+    // The thread allowed to run.
+    running: Option<usize>,
+
+    finished: Vec<bool>,
+
+    // The threads to choose at the first decisions.  The rest choose
+    // the first candidate.
+    prefix: Vec<usize>,
+
+    decisions: Vec<Decision>,
+    preemptions: usize,
+
+    // The messages of the threads that panicked.
+    panics: Vec<(usize, String)>,
+}
+
+#[cfg(feature = "failpoint_enabled")]
+impl SchedState {
+    // Chooses the thread to run next, after thread `current` yields,
+    // or after a thread finishes when `current` is None.
+    fn decide(&mut self, current: Option<usize>) {
+        let mut candidates: Vec<usize> = current.into_iter().collect();
+        candidates
+            .extend((0..self.finished.len()).filter(|&t| !self.finished[t] && Some(t) != current));
+
+        // Only choices between two or more threads are decisions.
+        if candidates.len() < 2 {
+            self.running = candidates.first().copied();
+            return;
+        }
+
+        let step = self.decisions.len();
+        let chosen = self
+            .prefix
+            .get(step)
+            .and_then(|t| candidates.iter().position(|c| c == t))
+            .unwrap_or(0);
+        self.decisions.push(Decision {
+            candidates: candidates.clone(),
+            chosen,
+            yielded: current.is_some(),
+            preemptions: self.preemptions,
+        });
+        if current.is_some() && chosen != 0 {
+            self.preemptions += 1;
+        }
+        self.running = Some(candidates[chosen]);
+    }
+}
+
+#[cfg(feature = "failpoint_enabled")]
+#[derive(Debug, Default)]
+struct Sched {
+    mu: Mutex<SchedState>,
+    cv: Condvar,
+}
+
+#[cfg(feature = "failpoint_enabled")]
+impl Sched {
+    fn lock(&self) -> MutexGuard<'_, SchedState> {
+        self.mu.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wait_turn<'a>(
+        &self,
+        mut g: MutexGuard<'a, SchedState>,
+        id: usize,
+    ) -> MutexGuard<'a, SchedState> {
+        while g.running != Some(id) {
+            g = self.cv.wait(g).unwrap_or_else(|e| e.into_inner());
+        }
+        g
+    }
+
+    fn yield_now(&self, id: usize) {
+        let mut g = self.lock();
+        g.decide(Some(id));
+        self.cv.notify_all();
+        drop(self.wait_turn(g, id));
+    }
+
+    fn finish(&self, id: usize, panic: Option<String>) {
+        let mut g = self.lock();
+        g.finished[id] = true;
+        if let Some(msg) = panic {
+            g.panics.push((id, msg));
+        }
+        g.decide(None);
+        self.cv.notify_all();
+    }
+}
+
+// Returns the message of a panic.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "a panic".to_string()
+    }
+}
+
+/// The threads of one run of an [`Explorer`]'s body.
+pub struct Scope {
+    #[cfg(feature = "failpoint_enabled")]
+    sched: Arc<Sched>,
+    handles: RefCell<Vec<JoinHandle<()>>>,
+    joined: Cell<bool>,
+}
+
+impl Scope {
+    /// Spawns a thread that runs `f` when the explorer schedules it.
+    /// The threads don't start until [`Scope::join()`] is called.
+    #[cfg(feature = "failpoint_enabled")]
+    pub fn spawn<F: FnOnce() + Send + 'static>(&self, f: F) {
+        let mut handles = self.handles.borrow_mut();
+        let id = handles.len();
+        self.sched.lock().finished.push(false);
+
+        let sched = self.sched.clone();
+        handles.push(thread::spawn(move || {
+            CURRENT.with(|c| *c.borrow_mut() = Some((sched.clone(), id)));
+            drop(sched.wait_turn(sched.lock(), id));
+            let panic = panic::catch_unwind(AssertUnwindSafe(f))
+                .err()
+                .map(|p| panic_message(p.as_ref()));
+            CURRENT.with(|c| *c.borrow_mut() = None);
+            sched.finish(id, panic);
+        }));
+    }
+
+    #[cfg(not(feature = "failpoint_enabled"))]
+    pub fn spawn<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.handles.borrow_mut().push(thread::spawn(f));
+    }
+
+    /// Runs the spawned threads, as scheduled, until they have all
+    /// finished.  Panics if any of them panicked.
+    ///
+    /// The explorer calls it after the body returns if the body
+    /// didn't.
+    pub fn join(&self) {
+        if self.joined.replace(true) {
+            return;
+        }
+
+        #[cfg(feature = "failpoint_enabled")]
+        {
+            let mut g = self.sched.lock();
+            g.decide(None);
+            self.sched.cv.notify_all();
+        }
+
+        let mut panics = Vec::new();
+        for (id, h) in self.handles.take().into_iter().enumerate() {
+            if let Err(p) = h.join() {
+                panics.push((id, panic_message(p.as_ref())));
+            }
+        }
+        #[cfg(feature = "failpoint_enabled")]
+        panics.append(&mut self.sched.lock().panics);
+
+        if let Some((id, msg)) = panics.first() {
+            panic!("thread {id} panicked: {msg}");
+        }
+    }
+}
+
+/// Runs a test body under every schedule of its threads, up to a
+/// preemption budget.  See the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct Explorer {
+    max_preemptions: usize,
+}
+
+impl Default for Explorer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Explorer {
+    /// Makes an explorer that allows two preemptions.
+    pub fn new() -> Self {
+        Self { max_preemptions: 2 }
+    }
+
+    /// Sets the number of preemptions allowed in a schedule.
+    pub fn max_preemptions(mut self, n: usize) -> Self {
+        self.max_preemptions = n;
+        self
+    }
+
+    /// Runs `body` once for each schedule, stopping at the first
+    /// schedule that makes it panic.
+    ///
+    /// `body` should make the state under test, spawn the threads with
+    /// [`Scope::spawn()`], call [`Scope::join()`] and then check the
+    /// state.
+    #[cfg(feature = "failpoint_enabled")]
+    pub fn run<F: Fn(&Scope)>(&self, body: F) -> InterleaveResult {
+        let mut result = InterleaveResult {
+            schedule_count: 0,
+            failure: None,
+        };
+        let mut prefix = Vec::new();
+
+        loop {
+            log_if_verbose(
+                Verbosity::Moderate,
+                format!(
+                    "Running schedule {}, starting {prefix:?}",
+                    result.schedule_count + 1
+                ),
+            );
+            let (decisions, failure) = self.run_schedule(&body, prefix);
+            result.schedule_count += 1;
+
+            if let Some(message) = failure {
+                let schedule = decisions.iter().map(|d| d.candidates[d.chosen]).collect();
+                log_if_verbose(
+                    Verbosity::None,
+                    format!("Schedule {schedule:?} failed: {message}"),
+                );
+                result.failure = Some(InterleaveFailure { schedule, message });
+                return result;
+            }
+
+            match self.next_prefix(&decisions) {
+                Some(p) => prefix = p,
+                None => return result,
+            }
+        }
+    }
+
+    #[cfg(not(feature = "failpoint_enabled"))]
+    pub fn run<F: Fn(&Scope)>(&self, body: F) -> InterleaveResult {
+        let scope = Scope {
+            handles: RefCell::new(Vec::new()),
+            joined: Cell::new(false),
+        };
+        let failure = panic::catch_unwind(AssertUnwindSafe(|| {
+            body(&scope);
+            scope.join();
+        }))
+        .err()
+        .map(|p| InterleaveFailure {
+            schedule: Vec::new(),
+            message: panic_message(p.as_ref()),
+        });
+        InterleaveResult {
+            schedule_count: 1,
+            failure,
+        }
+    }
+
+    // Runs `body` once, making the choices in `prefix` first.  Returns
+    // the decisions made, and the panic message if it failed.
+    #[cfg(feature = "failpoint_enabled")]
+    fn run_schedule<F: Fn(&Scope)>(
+        &self,
+        body: &F,
+        prefix: Vec<usize>,
+    ) -> (Vec<Decision>, Option<String>) {
+        let scope = Scope {
+            sched: Arc::new(Sched {
+                mu: Mutex::new(SchedState {
+                    prefix,
+                    ..SchedState::default()
+                }),
+                cv: Condvar::new(),
+            }),
+            handles: RefCell::new(Vec::new()),
+            joined: Cell::new(false),
+        };
+
+        start_counter();
+        let mut failure = panic::catch_unwind(AssertUnwindSafe(|| body(&scope)))
+            .err()
+            .map(|p| panic_message(p.as_ref()));
+        // Run the threads even if the body failed, so that none are
+        // left waiting.
+        if let Err(p) = panic::catch_unwind(AssertUnwindSafe(|| scope.join())) {
+            failure.get_or_insert_with(|| panic_message(p.as_ref()));
+        }
+
+        let decisions = std::mem::take(&mut scope.sched.lock().decisions);
+        (decisions, failure)
+    }
+
+    // Returns the prefix of the next schedule to run after the one
+    // that made `decisions`, or None if there are no more within the
+    // preemption budget.
+    #[cfg(feature = "failpoint_enabled")]
+    fn next_prefix(&self, decisions: &[Decision]) -> Option<Vec<usize>> {
+        for (i, d) in decisions.iter().enumerate().rev() {
+            // Choosing any later candidate preempts a thread that
+            // yielded.
+            if d.chosen + 1 < d.candidates.len()
+                && d.preemptions + usize::from(d.yielded) <= self.max_preemptions
+            {
+                let mut prefix: Vec<usize> = decisions[..i]
+                    .iter()
+                    .map(|d| d.candidates[d.chosen])
+                    .collect();
+                prefix.push(d.candidates[d.chosen + 1]);
+                return Some(prefix);
+            }
+        }
+        None
+    }
+}
+
+/// The result of [`Explorer::run()`].
+#[derive(Debug)]
+pub struct InterleaveResult {
+    /// The number of schedules run.
+    pub schedule_count: usize,
+
+    /// The schedule that failed, if one did.
+    pub failure: Option<InterleaveFailure>,
+}
+
+impl InterleaveResult {
+    pub fn success(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// A schedule that made an [`Explorer`]'s body panic.
+#[derive(Debug, Clone)]
+pub struct InterleaveFailure {
+    /// The thread chosen at each decision, where threads are numbered
+    /// in the order they were spawned.
+    pub schedule: Vec<usize>,
+
+    /// The panic message.
+    pub message: String,
+}
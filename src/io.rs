@@ -0,0 +1,345 @@
+//! Readers and writers with failpoints.
+//!
+//! [`FailRead`] and [`FailWrite`] wrap any reader or writer.  Every
+//! call to `read()`, `write()`, `flush()` and `seek()` passes through
+//! its own failpoint, so the I/O can be fault injected without
+//! putting a [`failpoint!`](crate::failpoint!) around every call site.
+//!
+//! When one of these failpoints is triggered the call to the wrapped
+//! reader or writer is skipped and an error is returned instead, or
+//! with [`IoFault::Short`] a read or write transfers fewer bytes than
+//! it was asked to.  With [`IoFault::Torn`] part of a write reaches
+//! the writer before the error is returned, to test recovery from a
+//! crash in the middle of a write.
+//!
+//! ```rust
+//! use std::io::{self, Read};
+//!
+//! use failpoint::io::FailRead;
+//! use failpoint::test_codepath;
+//!
+//! fn read_config<R: Read>(r: R) -> io::Result<String> {
+//!     let mut s = String::new();
+//!     FailRead::new(r).read_to_string(&mut s)?;
+//!     Ok(s)
+//! }
+//!
+//! let res = test_codepath! {
+//!     codepath {
+//!         read_config("a = 1".as_bytes())
+//!     }
+//! };
+//!
+//! // `read_to_string()` reads twice, once for the data and once to
+//! // find the end of the file.
+//! assert!(res.success());
+//! assert_eq!(res.expected_trigger_count, 2);
+//! ```
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::Location;
+#[cfg(feature = "failpoint_enabled")]
+use crate::lock_state;
+
+// Makes the `Location` of a failpoint in this crate.  The
+// description may be a `&'static str` or a `String`.
+macro_rules! location {
+    ($desc: expr) => {
+        $crate::Location {
+            crate_name: Some(env!("CARGO_CRATE_NAME")),
+            file_name: file!(),
+            line_no: line!(),
+            desc: Some($desc.into()),
+        }
+    };
+}
+pub(crate) use location;
+
+/// What a triggered failpoint in a [`FailRead`] or [`FailWrite`]
+/// injects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoFault {
+    /// Return an error of this kind.
+    Error(io::ErrorKind),
+
+    /// Make a read or write transfer fewer bytes than asked for, as
+    /// they are allowed to do.  Only half of the buffer is
+    /// transferred.  `flush()`, `seek()` and reads or writes of a
+    /// single byte can't be shortened, so they return an error of
+    /// kind [`io::ErrorKind::Other`] instead.
+    Short,
+
+    /// Make a write tear: part of the data reaches the writer and
+    /// then an error of kind [`io::ErrorKind::Other`] is returned, as
+    /// if the process crashed part way through the write.  Reads,
+    /// `flush()` and `seek()` just return the error.
+    Torn(TornWrite),
+}
+
+impl Default for IoFault {
+    fn default() -> Self {
+        IoFault::Error(io::ErrorKind::Other)
+    }
+}
+
+/// How much of a torn write survives.  See [`IoFault::Torn`].
+///
+/// Random lengths are chosen by a generator seeded with
+/// [`set_seed()`](crate::set_seed), so a torn write can be reproduced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TornWrite {
+    /// The first `n` bytes of the buffer are written, or all of it if
+    /// it is shorter.
+    Prefix(usize),
+
+    /// A random number of bytes from the start of the buffer are
+    /// written, possibly none, but never all of them.
+    RandomPrefix,
+
+    /// The file is truncated to `n` bytes, or left alone if it is
+    /// shorter, and nothing from the buffer is written.  Only a
+    /// [`fs::File`](crate::fs::File) can be truncated, other writers
+    /// write nothing.
+    Truncate(u64),
+
+    /// The file is truncated to a random length no longer than it
+    /// already is, losing data written earlier as an unsynced page
+    /// cache might.  Other writers write nothing.
+    RandomTruncate,
+}
+
+// What a write should do after reaching its failpoint.
+#[cfg_attr(not(feature = "failpoint_enabled"), allow(dead_code))]
+pub(crate) enum Transfer {
+    // Transfer up to this many bytes.
+    Bytes(usize),
+
+    // Write the first `keep` bytes of the buffer, truncate the file to
+    // `truncate` bytes if given, and then return `err`.
+    Torn {
+        keep: usize,
+        truncate: Option<u64>,
+        err: io::Error,
+    },
+}
+
+// Reaches the failpoint at `loc` for an operation on `len` bytes.
+// Returns the number of bytes the operation may transfer, or the
+// error to return instead.
+pub(crate) fn reach(loc: &Location, fault: IoFault, len: usize) -> io::Result<usize> {
+    // Only writes can tear.
+    let fault = match fault {
+        IoFault::Torn(_) => IoFault::default(),
+        f => f,
+    };
+    match reach_write(loc, fault, len, || Ok(None))? {
+        Transfer::Bytes(n) => Ok(n),
+        Transfer::Torn { err, .. } => Err(err),
+    }
+}
+
+// Reaches the failpoint at `loc` for a write of `len` bytes.
+// `file_len` returns the length of the writer, if it is a file, and
+// is only called when the write is torn by truncating it.
+#[cfg(feature = "failpoint_enabled")]
+pub(crate) fn reach_write(
+    loc: &Location,
+    fault: IoFault,
+    len: usize,
+    file_len: impl FnOnce() -> io::Result<Option<u64>>,
+) -> io::Result<Transfer> {
+    let mut g = lock_state();
+    if !g.active || !g.should_trigger(loc) {
+        return Ok(Transfer::Bytes(len));
+    }
+
+    match fault {
+        IoFault::Short if len > 1 => {
+            let short = len / 2;
+            g.report_fault(loc, &format!("a short transfer of {short} of {len} bytes"));
+            Ok(Transfer::Bytes(short))
+        }
+        IoFault::Short => {
+            // Can't be any shorter, so fail instead.
+            let err = injected_error(io::ErrorKind::Other, loc);
+            g.report_trigger(loc, &err);
+            Err(err)
+        }
+        IoFault::Error(kind) => {
+            let err = injected_error(kind, loc);
+            g.report_trigger(loc, &err);
+            Err(err)
+        }
+        IoFault::Torn(torn) => {
+            let (keep, truncate) = match torn {
+                TornWrite::Prefix(n) => (n.min(len), None),
+                TornWrite::RandomPrefix => {
+                    (g.rng.up_to(len.saturating_sub(1) as u64) as usize, None)
+                }
+                TornWrite::Truncate(n) => (0, file_len()?.map(|l| l.min(n))),
+                TornWrite::RandomTruncate => (0, file_len()?.map(|l| g.rng.up_to(l))),
+            };
+            let err = injected_error(io::ErrorKind::Other, loc);
+            let fault = match truncate {
+                Some(t) => format!("a torn write truncating the file to {t} bytes, then {err:?}"),
+                None => format!("a torn write of {keep} of {len} bytes, then {err:?}"),
+            };
+            g.report_fault(loc, &fault);
+            Ok(Transfer::Torn {
+                keep,
+                truncate,
+                err,
+            })
+        }
+    }
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+pub(crate) fn reach_write(
+    _loc: &Location,
+    _fault: IoFault,
+    len: usize,
+    _file_len: impl FnOnce() -> io::Result<Option<u64>>,
+) -> io::Result<Transfer> {
+    Ok(Transfer::Bytes(len))
+}
+
+/// Makes the error injected by the failpoint at `loc`.
+#[cfg(feature = "failpoint_enabled")]
+pub(crate) fn injected_error(kind: io::ErrorKind, loc: &Location) -> io::Error {
+    io::Error::new(kind, format!("injected by {}", loc.format()))
+}
+
+/// A reader whose `read()` and `seek()` calls are failpoints.
+///
+/// The failpoints are described as `FailRead::read` and
+/// `FailRead::seek`.
+#[derive(Debug)]
+pub struct FailRead<R> {
+    inner: R,
+    fault: IoFault,
+}
+
+impl<R> FailRead<R> {
+    /// Wraps `inner`.  Triggered failpoints return an error of kind
+    /// [`io::ErrorKind::Other`].
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            fault: IoFault::default(),
+        }
+    }
+
+    /// Sets what triggered failpoints inject.
+    pub fn with_fault(mut self, fault: IoFault) -> Self {
+        self.fault = fault;
+        self
+    }
+
+    /// Makes triggered failpoints return an error of kind `kind`.
+    pub fn with_error_kind(self, kind: io::ErrorKind) -> Self {
+        self.with_fault(IoFault::Error(kind))
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for FailRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = reach(&location!("FailRead::read"), self.fault, buf.len())?;
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+impl<R: Seek> Seek for FailRead<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        reach(&location!("FailRead::seek"), self.fault, 0)?;
+        self.inner.seek(pos)
+    }
+}
+
+/// A writer whose `write()`, `flush()` and `seek()` calls are
+/// failpoints.
+///
+/// The failpoints are described as `FailWrite::write`,
+/// `FailWrite::flush` and `FailWrite::seek`.
+#[derive(Debug)]
+pub struct FailWrite<W> {
+    inner: W,
+    fault: IoFault,
+}
+
+impl<W> FailWrite<W> {
+    /// Wraps `inner`.  Triggered failpoints return an error of kind
+    /// [`io::ErrorKind::Other`].
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            fault: IoFault::default(),
+        }
+    }
+
+    /// Sets what triggered failpoints inject.
+    pub fn with_fault(mut self, fault: IoFault) -> Self {
+        self.fault = fault;
+        self
+    }
+
+    /// Makes triggered failpoints return an error of kind `kind`.
+    pub fn with_error_kind(self, kind: io::ErrorKind) -> Self {
+        self.with_fault(IoFault::Error(kind))
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for FailWrite<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match reach_write(
+            &location!("FailWrite::write"),
+            self.fault,
+            buf.len(),
+            || Ok(None),
+        )? {
+            Transfer::Bytes(len) => self.inner.write(&buf[..len]),
+            Transfer::Torn { keep, err, .. } => {
+                self.inner.write_all(&buf[..keep])?;
+                Err(err)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        reach(&location!("FailWrite::flush"), self.fault, 0)?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for FailWrite<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        reach(&location!("FailWrite::seek"), self.fault, 0)?;
+        self.inner.seek(pos)
+    }
+}
@@ -88,19 +88,46 @@
 //! # }
 //! ```
 
+pub mod alloc;
 mod codepath_macros;
 mod codepath_state;
+#[cfg(all(feature = "control", unix))]
+pub mod control;
+mod crashpoint;
+mod failpoint_event;
 mod failpoint_macros;
 mod failpoint_state;
+pub mod fs;
+pub mod interleave;
+pub mod io;
+mod minimize;
+pub mod mpsc;
+pub mod net;
+mod pause;
+mod replay;
+#[cfg(feature = "failpoint_enabled")]
+mod rng;
+pub mod subprocess;
+
+pub use crashpoint::{Crash, catch_crash, crash};
+pub use failpoint_event::{EventLogger, FailpointEvent};
+pub use minimize::minimize_schedule;
+pub use pause::{arm_pause, pause_at, release, wait_until_paused};
+pub use replay::{Decision, Schedule, start_recording, start_replay, stop_recording, stop_replay};
 
 // Re-export public API from failpoint_state
 pub use failpoint_state::{
-    ActiveGuard, Location, Logger, Verbosity, get_count, get_counted_locs, get_triggered_locs,
-    is_active, is_enabled, log_if_verbose, set_active, set_logger, set_verbosity, start_counter,
-    start_trigger,
+    ActiveGuard, Location, Logger, Persistence, Session, Verbosity, collect_injected_locs,
+    corrupt_with, get_count, get_counted_locs, get_divergence, get_hit_counts, get_injected_count,
+    get_injected_locs, get_reached_count, get_triggered_locs, is_active, is_enabled, log_event,
+    log_if_verbose, set_active, set_event_logger, set_logger, set_persistence, set_seed,
+    set_verbosity, start_counter, start_trigger, start_trigger_hit, start_trigger_hits,
+    start_trigger_named, start_trigger_sequence, warn_if_verbose,
 };
 
 #[cfg(feature = "failpoint_enabled")]
-pub use failpoint_state::{Inner, Mode, State, get_state, lock_state};
+pub use failpoint_state::{Inner, Mode, State, StateGuard, get_state, lock_state};
 
-pub use codepath_state::CodePathResult;
+#[cfg(feature = "failpoint_enabled")]
+pub use codepath_state::run_iterations_parallel;
+pub use codepath_state::{CodePathResult, Iteration, IterationSpan, Limits, enter_iteration_span};
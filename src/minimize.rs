@@ -0,0 +1,187 @@
+// Shrinking failing schedules.
+//
+// A run that injects many faults and fails usually only needs a few of
+// them to fail.  minimize_schedule() delta debugs the injected faults
+// (Zeller's ddmin): it reruns the code path injecting subsets of them,
+// and their complements, keeping any smaller set that still fails,
+// until removing any single fault makes the failure go away.
+//
+// A fault is identified by the location of its failpoint and which hit
+// of it injected, as recorded in the schedule, rather than by its
+// ordinal.  Removing a fault can change the path that follows it, so
+// the ordinals of later faults may change, but their locations and
+// hits usually don't.
+
+#[cfg(feature = "failpoint_enabled")]
+use std::collections::HashMap;
+#[cfg(feature = "failpoint_enabled")]
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+use crate::{Location, Schedule};
+#[cfg(feature = "failpoint_enabled")]
+use crate::{
+    Verbosity,
+    failpoint_state::{Mode, lock_state},
+    log_if_verbose,
+    replay::Decision,
+    start_replay, stop_replay,
+};
+
+/// Shrinks the faults injected by a failing `schedule` to a minimal
+/// set that still fails, and returns the failpoints that inject them.
+///
+/// `codepath` runs the code path once and returns true if it failed.
+/// A run that panics is also counted as a failure.  The code path is
+/// run with the faults injected at the failpoints, and hits of them,
+/// recorded in the schedule, so it must be deterministic, and must not
+/// start a run itself.
+///
+/// Returns an error if the schedule doesn't match the code path when
+/// it is replayed, if it doesn't fail when it is run again, or if the
+/// minimal set of faults doesn't fail when it is run a last time to
+/// find where they are.
+///
+/// # Examples
+///
+/// ```rust
+/// use failpoint::failpoint;
+///
+/// fn step(name: &'static str) -> Result<(), String> {
+///     let res = Ok(());
+///     failpoint!(res, format!("{name} failed"), name)
+/// }
+///
+/// // Only fails if both "b" and "d" fail.
+/// fn run() -> bool {
+///     let r: Vec<bool> = ["a", "b", "c", "d"].iter().map(|n| step(n).is_err()).collect();
+///     r[1] && r[3]
+/// }
+///
+/// failpoint::start_recording();
+/// failpoint::start_trigger_sequence(&[1, 2, 3, 4]);
+/// assert!(run());
+/// let schedule = failpoint::stop_recording();
+///
+/// let locs = failpoint::minimize_schedule(&schedule, run).unwrap();
+/// let descs: Vec<_> = locs.iter().map(|l| l.desc.as_deref().unwrap()).collect();
+/// assert_eq!(descs, vec!["b", "d"]);
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+pub fn minimize_schedule(
+    schedule: &Schedule,
+    mut codepath: impl FnMut() -> bool,
+) -> Result<Vec<Location>, String> {
+    let mut run = || catch_unwind(AssertUnwindSafe(&mut codepath)).unwrap_or(true);
+
+    // Check that the schedule still reaches the failpoints it recorded,
+    // and still fails.
+    start_replay(schedule);
+    let failed = run();
+    if let Err(e) = stop_replay() {
+        return Err(format!("the schedule does not match the code path: {e}"));
+    }
+    if !failed {
+        return Err("the schedule does not fail when it is run again".to_string());
+    }
+
+    let faults = injected_faults(schedule);
+    let mut results: HashMap<Vec<usize>, bool> = HashMap::new();
+    let mut fails = |set: &[usize]| -> bool {
+        if let Some(&f) = results.get(set) {
+            return f;
+        }
+        start_injecting(set.iter().map(|&i| faults[i].clone()).collect());
+        let f = run();
+        results.insert(set.to_vec(), f);
+        f
+    };
+
+    let mut set: Vec<usize> = (0..faults.len()).collect();
+    let mut n = 2;
+    while set.len() >= 2 {
+        let chunk = set.len().div_ceil(n);
+        let subsets: Vec<Vec<usize>> = set.chunks(chunk).map(|c| c.to_vec()).collect();
+        let complements: Vec<Vec<usize>> = (0..subsets.len())
+            .map(|i| {
+                let mut c = set.clone();
+                c.drain(i * chunk..((i + 1) * chunk).min(set.len()));
+                c
+            })
+            .collect();
+
+        if let Some(s) = subsets.into_iter().find(|s| fails(s)) {
+            set = s;
+            n = 2;
+        } else if let Some(c) = complements.into_iter().find(|c| fails(c)) {
+            set = c;
+            n = (n - 1).max(2);
+        } else if n < set.len() {
+            n = (n * 2).min(set.len());
+        } else {
+            break;
+        }
+    }
+
+    // Run the minimal set once more, collecting where its faults are,
+    // and check that it still fails.
+    let minimal: Vec<(Decision, u64)> = set.iter().map(|&i| faults[i].clone()).collect();
+    log_if_verbose(
+        Verbosity::Moderate,
+        format!(
+            "Minimized schedule to {}",
+            minimal
+                .iter()
+                .map(|(d, _)| d.format())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    );
+    let prev = lock_state().injected_locs.replace(Vec::new());
+    start_injecting(minimal);
+    let failed = run();
+    let locs = std::mem::replace(&mut lock_state().injected_locs, prev).unwrap_or_default();
+    if !failed {
+        return Err("the minimal set of faults does not fail when it is run again".to_string());
+    }
+    Ok(locs.into_iter().map(|(_, loc)| loc).collect())
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+pub fn minimize_schedule(
+    _schedule: &Schedule,
+    _codepath: impl FnMut() -> bool,
+) -> Result<Vec<Location>, String> {
+    Ok(Vec::new())
+}
+
+// Returns the decisions in `schedule` that injected faults, each with
+// which hit of its failpoint it was.
+#[cfg(feature = "failpoint_enabled")]
+fn injected_faults(schedule: &Schedule) -> Vec<(Decision, u64)> {
+    let mut hits: HashMap<(&str, u32, Option<&str>), u64> = HashMap::new();
+    let mut faults = Vec::new();
+    for d in &schedule.decisions {
+        let hit = hits
+            .entry((d.file.as_str(), d.line, d.desc.as_deref()))
+            .or_insert(0);
+        *hit += 1;
+        if d.injected {
+            faults.push((d.clone(), *hit));
+        }
+    }
+    faults
+}
+
+// Starts a run that injects the faults of the failpoints in `faults`,
+// at the given hits of them, and no others.
+#[cfg(feature = "failpoint_enabled")]
+fn start_injecting(faults: Vec<(Decision, u64)>) {
+    let mut g = lock_state();
+    g.mode = Mode::Trigger;
+    g.trigger = i64::MAX;
+    g.trigger_desc = None;
+    g.trigger_hits = None;
+    g.trigger_sequence = Vec::new();
+    g.restart();
+    g.inject_at = Some(faults);
+}
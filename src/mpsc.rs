@@ -0,0 +1,340 @@
+//! Channels with failpoints.
+//!
+//! [`Sender`], [`SyncSender`] and [`Receiver`] wrap their
+//! [`std::sync::mpsc`] counterparts, and [`channel()`] and
+//! [`sync_channel()`] make them.  Sending and receiving are
+//! failpoints, described by the type and operation, for example
+//! `Receiver::recv`, so [`test_codepath!`](crate::test_codepath!)
+//! can check how a pipeline of worker threads handles a channel
+//! being disconnected or a receive timing out.
+//!
+//! When one of these failpoints is triggered the operation is not
+//! performed and the channel reports an error instead:
+//!
+//! | Operation                  | Error                                   |
+//! |----------------------------|-----------------------------------------|
+//! | `Sender::send`, `SyncSender::send` | `SendError`, with the value       |
+//! | `SyncSender::try_send`     | `TrySendError::Full`, with the value    |
+//! | `Receiver::recv`           | `RecvError`                             |
+//! | `Receiver::recv_timeout`   | `RecvTimeoutError::Timeout`             |
+//! | `Receiver::try_recv`       | `TryRecvError::Empty`                   |
+//!
+//! Use `with_fault(ChannelFault::Disconnected)` to make a
+//! [`SyncSender`] or [`Receiver`] report that the channel is
+//! disconnected instead.
+//!
+//! Failpoints reached on different threads are numbered in the order
+//! the threads reach them, so for repeatable results the threads
+//! should take turns, as a request and reply do.
+//!
+//! ```rust
+//! use std::thread;
+//!
+//! use failpoint::{mpsc, test_codepath};
+//!
+//! // Doubles a number on a worker thread.
+//! fn double(n: i32) -> Result<i32, String> {
+//!     let (tx, rx) = mpsc::channel::<i32>();
+//!     let (reply_tx, reply_rx) = mpsc::channel::<i32>();
+//!     tx.send(n).map_err(|e| e.to_string())?;
+//!
+//!     let worker = thread::spawn(move || {
+//!         if let Ok(n) = rx.recv() {
+//!             _ = reply_tx.send(n * 2);
+//!         }
+//!     });
+//!     worker.join().unwrap();
+//!
+//!     reply_rx.recv().map_err(|e| e.to_string())
+//! }
+//!
+//! let res = test_codepath! {
+//!     codepath {
+//!         double(21)
+//!     }
+//! };
+//!
+//! // Two sends and two receives.
+//! assert!(res.success());
+//! assert_eq!(res.expected_trigger_count, 4);
+//! ```
+
+use std::fmt;
+use std::sync::mpsc::{self, RecvError, RecvTimeoutError, SendError, TryRecvError, TrySendError};
+use std::time::Duration;
+
+#[cfg(feature = "failpoint_enabled")]
+use crate::io::location;
+#[cfg(feature = "failpoint_enabled")]
+use crate::lock_state;
+
+/// What a triggered failpoint on a [`SyncSender`] or [`Receiver`]
+/// reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChannelFault {
+    /// The error an operation reports when it can't complete yet:
+    /// `Full` from `try_send()`, `Timeout` from `recv_timeout()` and
+    /// `Empty` from `try_recv()`.  `send()` and `recv()` can only
+    /// report that the channel is disconnected.
+    #[default]
+    NotReady,
+
+    /// The channel is disconnected.
+    Disconnected,
+}
+
+// Reaches the failpoint described by `desc`, returning true if it was
+// triggered.
+#[cfg(feature = "failpoint_enabled")]
+fn reach(desc: &'static str, fault: &str) -> bool {
+    let loc = location!(desc);
+    let mut g = lock_state();
+    if !g.active || !g.should_trigger(&loc) {
+        return false;
+    }
+    g.report_fault(&loc, fault);
+    true
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+fn reach(_desc: &'static str, _fault: &str) -> bool {
+    false
+}
+
+/// Makes an unbounded channel, see [`std::sync::mpsc::channel()`].
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = mpsc::channel();
+    (Sender::new(tx), Receiver::new(rx))
+}
+
+/// Makes a bounded channel, see [`std::sync::mpsc::sync_channel()`].
+pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
+    let (tx, rx) = mpsc::sync_channel(bound);
+    (SyncSender::new(tx), Receiver::new(rx))
+}
+
+/// A [`std::sync::mpsc::Sender`] whose `send()` is a failpoint.
+pub struct Sender<T> {
+    inner: mpsc::Sender<T>,
+}
+
+impl<T> Sender<T> {
+    pub fn new(inner: mpsc::Sender<T>) -> Self {
+        Self { inner }
+    }
+
+    /// Sends `t`, see [`std::sync::mpsc::Sender::send()`].  A
+    /// failpoint described as `Sender::send`, which returns `t` in a
+    /// `SendError` when triggered.
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        if reach("Sender::send", "a disconnected channel") {
+            return Err(SendError(t));
+        }
+        self.inner.send(t)
+    }
+
+    pub fn get_ref(&self) -> &mpsc::Sender<T> {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> mpsc::Sender<T> {
+        self.inner
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").finish_non_exhaustive()
+    }
+}
+
+/// A [`std::sync::mpsc::SyncSender`] whose `send()` and `try_send()`
+/// are failpoints.
+pub struct SyncSender<T> {
+    inner: mpsc::SyncSender<T>,
+    fault: ChannelFault,
+}
+
+impl<T> SyncSender<T> {
+    pub fn new(inner: mpsc::SyncSender<T>) -> Self {
+        Self {
+            inner,
+            fault: ChannelFault::default(),
+        }
+    }
+
+    /// Sets what `try_send()` reports when its failpoint is triggered.
+    pub fn with_fault(mut self, fault: ChannelFault) -> Self {
+        self.fault = fault;
+        self
+    }
+
+    /// Sends `t`, waiting for space, see
+    /// [`std::sync::mpsc::SyncSender::send()`].  A failpoint described
+    /// as `SyncSender::send`, which returns `t` in a `SendError` when
+    /// triggered.
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        if reach("SyncSender::send", "a disconnected channel") {
+            return Err(SendError(t));
+        }
+        self.inner.send(t)
+    }
+
+    /// Sends `t` if there is space, see
+    /// [`std::sync::mpsc::SyncSender::try_send()`].  A failpoint
+    /// described as `SyncSender::try_send`.
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        match self.fault {
+            ChannelFault::NotReady if reach("SyncSender::try_send", "a full channel") => {
+                Err(TrySendError::Full(t))
+            }
+            ChannelFault::Disconnected
+                if reach("SyncSender::try_send", "a disconnected channel") =>
+            {
+                Err(TrySendError::Disconnected(t))
+            }
+            _ => self.inner.try_send(t),
+        }
+    }
+
+    pub fn get_ref(&self) -> &mpsc::SyncSender<T> {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> mpsc::SyncSender<T> {
+        self.inner
+    }
+}
+
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            fault: self.fault,
+        }
+    }
+}
+
+impl<T> fmt::Debug for SyncSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncSender").finish_non_exhaustive()
+    }
+}
+
+/// A [`std::sync::mpsc::Receiver`] whose `recv()`, `recv_timeout()`
+/// and `try_recv()` are failpoints.
+pub struct Receiver<T> {
+    inner: mpsc::Receiver<T>,
+    fault: ChannelFault,
+}
+
+impl<T> Receiver<T> {
+    pub fn new(inner: mpsc::Receiver<T>) -> Self {
+        Self {
+            inner,
+            fault: ChannelFault::default(),
+        }
+    }
+
+    /// Sets what `recv_timeout()` and `try_recv()` report when their
+    /// failpoints are triggered.
+    pub fn with_fault(mut self, fault: ChannelFault) -> Self {
+        self.fault = fault;
+        self
+    }
+
+    /// Waits for a value, see [`std::sync::mpsc::Receiver::recv()`].
+    /// A failpoint described as `Receiver::recv`.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        if reach("Receiver::recv", "a disconnected channel") {
+            return Err(RecvError);
+        }
+        self.inner.recv()
+    }
+
+    /// Waits for a value for at most `timeout`, see
+    /// [`std::sync::mpsc::Receiver::recv_timeout()`].  A failpoint
+    /// described as `Receiver::recv_timeout`.  The triggered failpoint
+    /// returns straight away, without waiting.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        match self.fault {
+            ChannelFault::NotReady if reach("Receiver::recv_timeout", "a timeout") => {
+                Err(RecvTimeoutError::Timeout)
+            }
+            ChannelFault::Disconnected
+                if reach("Receiver::recv_timeout", "a disconnected channel") =>
+            {
+                Err(RecvTimeoutError::Disconnected)
+            }
+            _ => self.inner.recv_timeout(timeout),
+        }
+    }
+
+    /// Returns a value if there is one, see
+    /// [`std::sync::mpsc::Receiver::try_recv()`].  A failpoint
+    /// described as `Receiver::try_recv`.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        match self.fault {
+            ChannelFault::NotReady if reach("Receiver::try_recv", "an empty channel") => {
+                Err(TryRecvError::Empty)
+            }
+            ChannelFault::Disconnected if reach("Receiver::try_recv", "a disconnected channel") => {
+                Err(TryRecvError::Disconnected)
+            }
+            _ => self.inner.try_recv(),
+        }
+    }
+
+    /// Returns an iterator that calls [`Receiver::recv()`] until it
+    /// fails, so each value received is a failpoint.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { rx: self }
+    }
+
+    pub fn get_ref(&self) -> &mpsc::Receiver<T> {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> mpsc::Receiver<T> {
+        self.inner
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+/// An iterator over the values received by a [`Receiver`].  See
+/// [`Receiver::iter()`].
+#[derive(Debug)]
+pub struct Iter<'a, T> {
+    rx: &'a Receiver<T>,
+}
+
+impl<T> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
@@ -0,0 +1,358 @@
+//! Network sockets with failpoints.
+//!
+//! [`TcpStream`], [`TcpListener`] and [`UdpSocket`] wrap their
+//! [`std::net`] counterparts.  Connecting, binding, accepting,
+//! reading and writing are failpoints, described by the type and
+//! operation, for example `TcpStream::read`, so
+//! [`test_codepath!`](crate::test_codepath!) can check that every
+//! lost connection is handled.
+//!
+//! When one of these failpoints is triggered the operation is not
+//! performed and an error of the kind the operation would fail with
+//! when a connection is lost is returned instead:
+//!
+//! | Operation                       | Error kind          |
+//! |---------------------------------|---------------------|
+//! | `TcpStream::connect`            | `ConnectionRefused` |
+//! | `TcpStream::connect_timeout`    | `TimedOut`          |
+//! | `TcpStream::read`               | `ConnectionReset`   |
+//! | `TcpStream::write`, `flush`     | `BrokenPipe`        |
+//! | `TcpListener::bind`, `UdpSocket::bind` | `AddrInUse`  |
+//! | `TcpListener::accept`           | `ConnectionAborted` |
+//! | `UdpSocket::send`, `send_to`    | `ConnectionRefused` |
+//! | `UdpSocket::recv`, `recv_from`  | `TimedOut`          |
+//!
+//! A socket's reads and writes can inject something else with
+//! `with_fault()` or `with_error_kind()`.  Streams accepted by a
+//! [`TcpListener`] inherit its fault.
+//!
+//! ```rust
+//! use std::io::{self, Read, Write};
+//!
+//! use failpoint::net::{TcpListener, TcpStream};
+//! use failpoint::test_codepath;
+//!
+//! let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+//! let addr = listener.local_addr().unwrap();
+//! let server = std::thread::spawn(move || {
+//!     for stream in listener.incoming() {
+//!         let mut stream = stream.unwrap();
+//!         let mut buf = [0u8; 4];
+//!         if stream.read_exact(&mut buf).is_ok() {
+//!             _ = stream.write_all(&buf);
+//!         }
+//!     }
+//! });
+//!
+//! fn ping(addr: std::net::SocketAddr) -> io::Result<()> {
+//!     let mut stream = TcpStream::connect(addr)?;
+//!     stream.write_all(b"ping")?;
+//!     let mut buf = [0u8; 4];
+//!     stream.read_exact(&mut buf)
+//! }
+//!
+//! let res = test_codepath! {
+//!     codepath {
+//!         ping(addr)
+//!     }
+//! };
+//!
+//! // connect, write and read.
+//! assert!(res.success());
+//! assert_eq!(res.expected_trigger_count, 3);
+//! ```
+
+use std::io::{self, Read, Write};
+use std::net::{self, Shutdown, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::io::{IoFault, Transfer, location, reach, reach_write};
+
+// Returns an error of kind `kind` if the failpoint at `desc` is
+// triggered.
+macro_rules! fail {
+    ($desc: expr, $kind: expr) => {
+        reach(&location!($desc), IoFault::Error($kind), 0).map(|_| ())
+    };
+}
+
+/// A [`std::net::TcpStream`] whose operations are failpoints.
+#[derive(Debug)]
+pub struct TcpStream {
+    inner: net::TcpStream,
+    fault: Option<IoFault>,
+}
+
+impl TcpStream {
+    /// Wraps a connected stream.
+    pub fn new(inner: net::TcpStream) -> Self {
+        Self { inner, fault: None }
+    }
+
+    /// Opens a connection to `addr`, see
+    /// [`std::net::TcpStream::connect()`].  A failpoint described as
+    /// `TcpStream::connect`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+        fail!("TcpStream::connect", io::ErrorKind::ConnectionRefused)?;
+        Ok(Self::new(net::TcpStream::connect(addr)?))
+    }
+
+    /// Opens a connection to `addr` with a timeout, see
+    /// [`std::net::TcpStream::connect_timeout()`].  A failpoint
+    /// described as `TcpStream::connect_timeout`.
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        fail!("TcpStream::connect_timeout", io::ErrorKind::TimedOut)?;
+        Ok(Self::new(net::TcpStream::connect_timeout(addr, timeout)?))
+    }
+
+    /// Sets what reads and writes inject when their failpoints are
+    /// triggered.
+    pub fn with_fault(mut self, fault: IoFault) -> Self {
+        self.fault = Some(fault);
+        self
+    }
+
+    /// Makes triggered reads and writes return an error of kind
+    /// `kind`.
+    pub fn with_error_kind(self, kind: io::ErrorKind) -> Self {
+        self.with_fault(IoFault::Error(kind))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(dur)
+    }
+
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(dur)
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    /// Clones the stream, see [`std::net::TcpStream::try_clone()`].
+    /// The clone injects the same fault.
+    pub fn try_clone(&self) -> io::Result<TcpStream> {
+        Ok(Self {
+            inner: self.inner.try_clone()?,
+            fault: self.fault,
+        })
+    }
+
+    pub fn get_ref(&self) -> &net::TcpStream {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut net::TcpStream {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> net::TcpStream {
+        self.inner
+    }
+
+    fn fault(&self, kind: io::ErrorKind) -> IoFault {
+        self.fault.unwrap_or(IoFault::Error(kind))
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let fault = self.fault(io::ErrorKind::ConnectionReset);
+        let len = reach(&location!("TcpStream::read"), fault, buf.len())?;
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let fault = self.fault(io::ErrorKind::BrokenPipe);
+        match reach_write(&location!("TcpStream::write"), fault, buf.len(), || {
+            Ok(None)
+        })? {
+            Transfer::Bytes(len) => self.inner.write(&buf[..len]),
+            Transfer::Torn { keep, err, .. } => {
+                self.inner.write_all(&buf[..keep])?;
+                Err(err)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let fault = self.fault(io::ErrorKind::BrokenPipe);
+        reach(&location!("TcpStream::flush"), fault, 0)?;
+        self.inner.flush()
+    }
+}
+
+/// A [`std::net::TcpListener`] whose operations are failpoints.
+#[derive(Debug)]
+pub struct TcpListener {
+    inner: net::TcpListener,
+    fault: Option<IoFault>,
+}
+
+impl TcpListener {
+    /// Wraps a listener.
+    pub fn new(inner: net::TcpListener) -> Self {
+        Self { inner, fault: None }
+    }
+
+    /// Creates a listener bound to `addr`, see
+    /// [`std::net::TcpListener::bind()`].  A failpoint described as
+    /// `TcpListener::bind`.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+        fail!("TcpListener::bind", io::ErrorKind::AddrInUse)?;
+        Ok(Self::new(net::TcpListener::bind(addr)?))
+    }
+
+    /// Sets what the reads and writes of accepted streams inject when
+    /// their failpoints are triggered.
+    pub fn with_fault(mut self, fault: IoFault) -> Self {
+        self.fault = Some(fault);
+        self
+    }
+
+    /// Makes the triggered reads and writes of accepted streams
+    /// return an error of kind `kind`.
+    pub fn with_error_kind(self, kind: io::ErrorKind) -> Self {
+        self.with_fault(IoFault::Error(kind))
+    }
+
+    /// Accepts a connection, see [`std::net::TcpListener::accept()`].
+    /// A failpoint described as `TcpListener::accept`.
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        fail!("TcpListener::accept", io::ErrorKind::ConnectionAborted)?;
+        let (stream, addr) = self.inner.accept()?;
+        let stream = TcpStream {
+            inner: stream,
+            fault: self.fault,
+        };
+        Ok((stream, addr))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn get_ref(&self) -> &net::TcpListener {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> net::TcpListener {
+        self.inner
+    }
+}
+
+/// A [`std::net::UdpSocket`] whose operations are failpoints.
+#[derive(Debug)]
+pub struct UdpSocket {
+    inner: net::UdpSocket,
+    fault: Option<IoFault>,
+}
+
+impl UdpSocket {
+    /// Wraps a socket.
+    pub fn new(inner: net::UdpSocket) -> Self {
+        Self { inner, fault: None }
+    }
+
+    /// Creates a socket bound to `addr`, see
+    /// [`std::net::UdpSocket::bind()`].  A failpoint described as
+    /// `UdpSocket::bind`.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
+        fail!("UdpSocket::bind", io::ErrorKind::AddrInUse)?;
+        Ok(Self::new(net::UdpSocket::bind(addr)?))
+    }
+
+    /// Sets what sends and receives inject when their failpoints are
+    /// triggered.  [`IoFault::Short`] truncates the datagram.
+    pub fn with_fault(mut self, fault: IoFault) -> Self {
+        self.fault = Some(fault);
+        self
+    }
+
+    /// Makes triggered sends and receives return an error of kind
+    /// `kind`.
+    pub fn with_error_kind(self, kind: io::ErrorKind) -> Self {
+        self.with_fault(IoFault::Error(kind))
+    }
+
+    /// Connects the socket to `addr`, see
+    /// [`std::net::UdpSocket::connect()`].  Not a failpoint, as no
+    /// packets are sent.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        self.inner.connect(addr)
+    }
+
+    /// Sends a datagram to `addr`, see
+    /// [`std::net::UdpSocket::send_to()`].  A failpoint described as
+    /// `UdpSocket::send_to`.
+    pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
+        let fault = self.fault(io::ErrorKind::ConnectionRefused);
+        let len = reach(&location!("UdpSocket::send_to"), fault, buf.len())?;
+        self.inner.send_to(&buf[..len], addr)
+    }
+
+    /// Receives a datagram, see [`std::net::UdpSocket::recv_from()`].
+    /// A failpoint described as `UdpSocket::recv_from`.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let fault = self.fault(io::ErrorKind::TimedOut);
+        let len = reach(&location!("UdpSocket::recv_from"), fault, buf.len())?;
+        self.inner.recv_from(&mut buf[..len])
+    }
+
+    /// Sends a datagram to the connected address, see
+    /// [`std::net::UdpSocket::send()`].  A failpoint described as
+    /// `UdpSocket::send`.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let fault = self.fault(io::ErrorKind::ConnectionRefused);
+        let len = reach(&location!("UdpSocket::send"), fault, buf.len())?;
+        self.inner.send(&buf[..len])
+    }
+
+    /// Receives a datagram from the connected address, see
+    /// [`std::net::UdpSocket::recv()`].  A failpoint described as
+    /// `UdpSocket::recv`.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let fault = self.fault(io::ErrorKind::TimedOut);
+        let len = reach(&location!("UdpSocket::recv"), fault, buf.len())?;
+        self.inner.recv(&mut buf[..len])
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(dur)
+    }
+
+    pub fn get_ref(&self) -> &net::UdpSocket {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> net::UdpSocket {
+        self.inner
+    }
+
+    fn fault(&self, kind: io::ErrorKind) -> IoFault {
+        self.fault.unwrap_or(IoFault::Error(kind))
+    }
+}
@@ -0,0 +1,124 @@
+// Pause points, for interleaving threads at exact locations.
+//
+// A thread that reaches an armed `failpoint_pause!` blocks until the
+// test releases it.  The test arms the pause point with arm_pause(),
+// waits for a thread to stop there with wait_until_paused(), does
+// whatever it wants to race with the paused thread, and then lets it
+// continue with release().
+//
+// Pause points are identified by their description.  Unlike other
+// failpoints they are not counted or triggered, so adding one doesn't
+// change the ordinals of the failpoints around it.  A pause point
+// that isn't armed does nothing.
+
+#[cfg(feature = "failpoint_enabled")]
+use std::collections::HashMap;
+#[cfg(feature = "failpoint_enabled")]
+use std::sync::{Condvar, LazyLock, Mutex, MutexGuard};
+
+use crate::Location;
+#[cfg(feature = "failpoint_enabled")]
+use crate::{Verbosity, log_if_verbose};
+
+// The state of an armed pause point.
+#[cfg(feature = "failpoint_enabled")]
+#[derive(Debug, Default)]
+struct Pause {
+    // Where a thread is paused, if one is.
+    paused: Option<Location>,
+
+    // Set by release() to let the paused thread continue.
+    released: bool,
+}
+
+#[cfg(feature = "failpoint_enabled")]
+#[derive(Default)]
+struct Pauses {
+    // The armed pause points, by description.
+    mu: Mutex<HashMap<String, Pause>>,
+
+    // Notified whenever a thread pauses or is released.
+    cv: Condvar,
+}
+
+#[cfg(feature = "failpoint_enabled")]
+static PAUSES: LazyLock<Pauses> = LazyLock::new(Pauses::default);
+
+#[cfg(feature = "failpoint_enabled")]
+fn lock_pauses() -> MutexGuard<'static, HashMap<String, Pause>> {
+    PAUSES.mu.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Arms the pause point described by `desc`, so that the next thread
+/// to reach it blocks until [`release()`] is called.
+#[cfg(feature = "failpoint_enabled")]
+pub fn arm_pause(desc: &str) {
+    lock_pauses().insert(desc.to_string(), Pause::default());
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+pub fn arm_pause(_desc: &str) {}
+
+/// Blocks until a thread is paused at the armed pause point described
+/// by `desc`.  Returns straight away if the pause point isn't armed.
+#[cfg(feature = "failpoint_enabled")]
+pub fn wait_until_paused(desc: &str) {
+    let mut g = lock_pauses();
+    while g.get(desc).is_some_and(|p| p.paused.is_none()) {
+        g = PAUSES.cv.wait(g).unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+pub fn wait_until_paused(_desc: &str) {}
+
+/// Disarms the pause point described by `desc`, letting the thread
+/// paused there, if there is one, continue.
+#[cfg(feature = "failpoint_enabled")]
+pub fn release(desc: &str) {
+    let mut g = lock_pauses();
+    match g.get_mut(desc) {
+        Some(p) if p.paused.is_some() => p.released = true,
+        _ => {
+            g.remove(desc);
+        }
+    }
+    PAUSES.cv.notify_all();
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+pub fn release(_desc: &str) {}
+
+// See HIDDEN DOC in failpoint_state.rs.
+//
+// Blocks at `loc` if its pause point is armed and no other thread is
+// paused there.
+#[cfg(feature = "failpoint_enabled")]
+#[doc(hidden)]
+pub fn pause_at(loc: Location) {
+    let Some(desc) = loc.desc.clone() else {
+        return;
+    };
+    let mut g = lock_pauses();
+    match g.get_mut(desc.as_ref()) {
+        Some(p) if p.paused.is_none() => p.paused = Some(loc.clone()),
+        _ => return,
+    }
+    log_if_verbose(Verbosity::Moderate, format!("Paused at {}", loc.format()));
+    PAUSES.cv.notify_all();
+
+    while g.get(desc.as_ref()).is_some_and(|p| !p.released) {
+        g = PAUSES.cv.wait(g).unwrap_or_else(|e| e.into_inner());
+    }
+    g.remove(desc.as_ref());
+    drop(g);
+    log_if_verbose(Verbosity::Moderate, format!("Released at {}", loc.format()));
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[doc(hidden)]
+#[inline]
+pub fn pause_at(_loc: Location) {}
@@ -0,0 +1,316 @@
+// Recording and replaying the decisions made at failpoints.
+//
+// While recording, every failpoint reached appends a Decision: its
+// ordinal, where it is, and whether it injected its fault.  The
+// recording restarts whenever a run starts, so stop_recording()
+// returns the decisions of the last run.  A Schedule can be saved to a
+// file and loaded again, for instance to reproduce a failure seen in
+// CI.
+//
+// While replaying, each failpoint makes exactly the recorded decision,
+// whatever the mode.  A failpoint that isn't the one recorded at its
+// ordinal is warned about and stops the replay, and stop_replay()
+// reports it.  It can't panic there, since failpoints are also
+// reached inside the allocator.
+//
+// The seed is saved with the decisions, so that random faults, such
+// as the length of a torn write, are the same when replayed.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::Location;
+#[cfg(feature = "failpoint_enabled")]
+use crate::failpoint_state::{Mode, lock_state};
+
+const HEADER: &str = "# failpoint schedule";
+const SEED: &str = ", seed ";
+
+/// A decision made at a failpoint, as recorded in a [`Schedule`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    /// The position of the failpoint in the run, starting at 1.
+    pub ordinal: i64,
+    pub file: String,
+    pub line: u32,
+    pub desc: Option<String>,
+    /// Whether the failpoint injected its fault.
+    pub injected: bool,
+}
+
+impl Decision {
+    pub fn new(ordinal: i64, loc: &Location, injected: bool) -> Self {
+        Decision {
+            ordinal,
+            file: loc.file_name.to_string(),
+            line: loc.line_no,
+            desc: loc.desc.as_ref().map(|d| d.to_string()),
+            injected,
+        }
+    }
+
+    /// Returns true if the decision was made at `loc`.
+    pub fn matches(&self, loc: &Location) -> bool {
+        self.file == loc.file_name
+            && self.line == loc.line_no
+            && self.desc.as_deref() == loc.desc.as_deref()
+    }
+
+    pub fn format(&self) -> String {
+        match self.desc {
+            Some(ref d) => format!("Failpoint \"{d}\" at {}:{}", self.file, self.line),
+            None => format!("Failpoint at {}:{}", self.file, self.line),
+        }
+    }
+}
+
+/// The decisions made at the failpoints during a run, in order.
+///
+/// A schedule is produced by [`stop_recording()`] and forced on a
+/// later run by [`start_replay()`].  It is saved as text, a header
+/// with the seed, then one decision per line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    /// The seed random faults were chosen from.  See
+    /// [`set_seed()`](crate::set_seed).
+    pub seed: u64,
+    pub decisions: Vec<Decision>,
+}
+
+impl Schedule {
+    /// Writes the schedule to the file at `path`.
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_string())
+    }
+
+    /// Reads a schedule written by [`save()`](Schedule::save).
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Schedule> {
+        let text = std::fs::read_to_string(path)?;
+        text.parse()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Returns the ordinals of the decisions that injected faults.
+    pub fn injected_ordinals(&self) -> Vec<i64> {
+        self.decisions
+            .iter()
+            .filter(|d| d.injected)
+            .map(|d| d.ordinal)
+            .collect()
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            _ => return Err(format!("bad escape in {s:?}")),
+        }
+    }
+    Ok(out)
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{HEADER}{SEED}{}", self.seed)?;
+        for d in &self.decisions {
+            let action = if d.injected { "inject" } else { "pass" };
+            write!(
+                f,
+                "{}\t{action}\t{}\t{}",
+                d.ordinal,
+                escape(&d.file),
+                d.line
+            )?;
+            if let Some(ref desc) = d.desc {
+                write!(f, "\t{}", escape(desc))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut seed = 0;
+        let mut decisions = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let bad = |what: &str| format!("line {}: {what}: {line:?}", i + 1);
+            if let Some(h) = line.strip_prefix(HEADER) {
+                if let Some(n) = h.strip_prefix(SEED) {
+                    seed = n.parse().map_err(|_| bad("bad seed"))?;
+                }
+                continue;
+            }
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 4 || fields.len() > 5 {
+                return Err(bad("wrong number of fields"));
+            }
+            let ordinal = fields[0].parse().map_err(|_| bad("bad ordinal"))?;
+            let injected = match fields[1] {
+                "inject" => true,
+                "pass" => false,
+                _ => return Err(bad("expected inject or pass")),
+            };
+            let file = unescape(fields[2]).map_err(|e| bad(&e))?;
+            let line_no = fields[3].parse().map_err(|_| bad("bad line number"))?;
+            let desc = match fields.get(4) {
+                Some(d) => Some(unescape(d).map_err(|e| bad(&e))?),
+                None => None,
+            };
+            decisions.push(Decision {
+                ordinal,
+                file,
+                line: line_no,
+                desc,
+                injected,
+            });
+        }
+        Ok(Schedule { seed, decisions })
+    }
+}
+
+/// Starts recording the decisions made at failpoints.
+///
+/// The recording restarts whenever a run starts, that is, whenever
+/// [`start_counter()`](crate::start_counter), one of the
+/// `start_trigger` functions or [`start_replay()`] is called, so
+/// [`stop_recording()`] returns the decisions made since the last of
+/// them.
+///
+/// # Examples
+///
+/// ```rust
+/// use failpoint::failpoint;
+///
+/// fn step() -> Result<(), String> {
+///     let res = Ok(());
+///     failpoint!(res, "failed".to_string(), "step")
+/// }
+///
+/// failpoint::start_recording();
+/// failpoint::start_trigger(2);
+/// _ = step();
+/// _ = step();
+/// let schedule = failpoint::stop_recording();
+/// assert_eq!(schedule.injected_ordinals(), vec![2]);
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+pub fn start_recording() {
+    let mut g = lock_state();
+    g.recording = Some(Vec::new());
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+pub fn start_recording() {}
+
+/// Stops recording and returns the decisions recorded since the run
+/// started.
+#[cfg(feature = "failpoint_enabled")]
+pub fn stop_recording() -> Schedule {
+    let mut g = lock_state();
+    Schedule {
+        seed: g.seed,
+        decisions: g.recording.take().unwrap_or_default(),
+    }
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+pub fn stop_recording() -> Schedule {
+    Schedule::default()
+}
+
+/// Starts a run that makes exactly the decisions in `schedule`.
+///
+/// Each failpoint reached injects its fault if, and only if, the
+/// decision recorded at its ordinal did.  The seed is set to the
+/// schedule's, as by [`set_seed()`](crate::set_seed), so random
+/// faults are the same as when it was recorded.  If a failpoint is
+/// reached that isn't the one recorded at its ordinal, or the run goes
+/// past the end of the schedule, a warning is logged, no more faults
+/// are injected, and [`stop_replay()`] returns the error.
+///
+/// # Examples
+///
+/// ```rust
+/// use failpoint::failpoint;
+///
+/// fn step() -> Result<(), String> {
+///     let res = Ok(());
+///     failpoint!(res, "failed".to_string(), "step")
+/// }
+///
+/// failpoint::start_recording();
+/// failpoint::start_trigger(2);
+/// _ = step();
+/// _ = step();
+/// let schedule = failpoint::stop_recording();
+///
+/// failpoint::start_replay(&schedule);
+/// assert!(step().is_ok());
+/// assert!(step().is_err());
+/// assert_eq!(failpoint::stop_replay(), Ok(()));
+/// ```
+#[cfg(feature = "failpoint_enabled")]
+pub fn start_replay(schedule: &Schedule) {
+    let mut g = lock_state();
+    g.mode = Mode::Trigger;
+    g.trigger = i64::MAX;
+    g.trigger_desc = None;
+    g.trigger_hits = None;
+    g.trigger_sequence = Vec::new();
+    g.seed = schedule.seed;
+    g.restart();
+    g.replay = Some(schedule.decisions.clone());
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+#[inline]
+pub fn start_replay(_schedule: &Schedule) {}
+
+/// Stops replaying.  Returns an error if the run reached a failpoint
+/// other than the one recorded, or didn't reach all the recorded
+/// failpoints.
+#[cfg(feature = "failpoint_enabled")]
+pub fn stop_replay() -> Result<(), String> {
+    let mut g = lock_state();
+    let replay = g.replay.take().unwrap_or_default();
+    if let Some(e) = g.replay_error.take() {
+        return Err(e);
+    }
+    match replay.get(g.ordinal as usize) {
+        Some(d) => Err(format!(
+            "replay ended at ordinal {}: expected {}",
+            g.ordinal,
+            d.format()
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "failpoint_enabled"))]
+pub fn stop_replay() -> Result<(), String> {
+    Ok(())
+}
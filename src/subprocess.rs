@@ -0,0 +1,282 @@
+//! Crash testing by re-running the test binary in a subprocess.
+//!
+//! [`crashpoint!`](crate::crashpoint!) unwinds the stack, so it can't
+//! model what a real crash leaves behind: unflushed buffers, writes
+//! that were never synced, and so on.  A [`CrashTest`] instead runs
+//! the test in a child process and aborts the child with
+//! [`std::process::abort()`] when the chosen failpoint is reached.
+//!
+//! The test binary is re-executed with an environment variable
+//! selecting the failpoint to trigger.  First a child runs in count
+//! mode to find how many failpoints there are, then one child is run
+//! for each failpoint `N`, and after each one crashes the parent runs
+//! a recovery check.
+//!
+//! ```rust,no_run
+//! use std::path::Path;
+//!
+//! use failpoint::subprocess::CrashTest;
+//!
+//! fn append(path: &Path, rec: &str) -> std::io::Result<()> {
+//!     // ... write the record with failpoint::fs ...
+//!     # Ok(())
+//! }
+//!
+//! fn check_log(path: &Path) -> Result<(), String> {
+//!     // ... verify the log is readable ...
+//!     # Ok(())
+//! }
+//!
+//! #[test]
+//! fn test_append_crash() {
+//!     // The parent and the children must agree on the path.
+//!     let path = std::env::temp_dir().join("append_crash.log");
+//!
+//!     let res = CrashTest::new("test_append_crash")
+//!         .before(|| _ = std::fs::remove_file(&path))
+//!         .run(
+//!             || append(&path, "hello").unwrap(),
+//!             |_ordinal| check_log(&path),
+//!         );
+//!     assert!(res.success());
+//! }
+//! ```
+//!
+//! The child runs the same test function, so everything before the
+//! call to [`CrashTest::run()`] runs in the children too, and must not
+//! depend on anything that differs between processes.
+
+use std::fmt::Debug;
+#[cfg(feature = "failpoint_enabled")]
+use std::path::PathBuf;
+#[cfg(feature = "failpoint_enabled")]
+use std::process::{self, Command};
+
+#[cfg(feature = "failpoint_enabled")]
+use crate::{Verbosity, get_count, lock_state, log_if_verbose, start_counter, start_trigger};
+
+/// The environment variable that selects the failpoint a child
+/// aborts at.  `0` makes the child count the failpoints instead.
+pub const TRIGGER_ENV: &str = "FAILPOINT_CRASH_TRIGGER";
+
+// The environment variable naming the file a counting child writes
+// its count to.
+#[cfg(feature = "failpoint_enabled")]
+const COUNT_FILE_ENV: &str = "FAILPOINT_CRASH_COUNT_FILE";
+
+// The exit code of a test binary whose test failed.
+#[cfg(feature = "failpoint_enabled")]
+const TEST_FAILED: i32 = 101;
+
+/// Runs a test in child processes, crashing each one at a different
+/// failpoint.
+#[cfg_attr(not(feature = "failpoint_enabled"), allow(dead_code))]
+pub struct CrashTest<'a> {
+    test_name: String,
+    before: Option<Box<dyn FnMut() + 'a>>,
+}
+
+impl<'a> CrashTest<'a> {
+    /// Makes a crash test that re-runs the test named `test_name`,
+    /// which must be the test's full path as printed by `cargo test`,
+    /// for example `tests::test_append_crash` for a test in a `tests`
+    /// module.
+    pub fn new(test_name: &str) -> Self {
+        Self {
+            test_name: test_name.to_string(),
+            before: None,
+        }
+    }
+
+    /// Sets a function that the parent runs before starting each
+    /// child, to reset anything the previous child left behind.
+    pub fn before<F: FnMut() + 'a>(mut self, f: F) -> Self {
+        self.before = Some(Box::new(f));
+        self
+    }
+
+    /// Runs the crash test.
+    ///
+    /// In a child process, runs `body` with failpoints counting, or
+    /// aborting the process at the selected failpoint, and then exits
+    /// the child without returning.
+    ///
+    /// In the parent, runs a counting child, and then a child for
+    /// each failpoint it counted.  After each child has crashed,
+    /// `recover` is called with the ordinal of the failpoint the child
+    /// crashed at.  Stops at the first child that doesn't crash as
+    /// expected, or the first recovery that returns an error.
+    #[cfg(feature = "failpoint_enabled")]
+    pub fn run<B, R, E>(mut self, body: B, mut recover: R) -> CrashTestResult
+    where
+        B: FnOnce(),
+        R: FnMut(i64) -> Result<(), E>,
+        E: Debug,
+    {
+        if let Ok(trigger) = std::env::var(TRIGGER_ENV) {
+            run_child(&trigger, body);
+        }
+
+        let count_file = std::env::temp_dir().join(format!(
+            "failpoint-crash-{}-{}.count",
+            process::id(),
+            self.test_name.replace("::", "-")
+        ));
+
+        let mut result = CrashTestResult {
+            expected_crash_count: 0,
+            crash_count: 0,
+            failure: None,
+        };
+
+        if let Some(f) = self.before.as_mut() {
+            f();
+        }
+        log_if_verbose(
+            Verbosity::Moderate,
+            "Running crash test child in COUNT mode".to_string(),
+        );
+        match self.run_child_process(0, Some(&count_file)) {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => {
+                result.failure = Some(describe("counting child failed", &output));
+                return result;
+            }
+            Err(e) => {
+                result.failure = Some(format!("failed to run counting child: {e}"));
+                return result;
+            }
+        }
+        let count = std::fs::read_to_string(&count_file)
+            .ok()
+            .and_then(|s| s.trim().parse::<i64>().ok());
+        _ = std::fs::remove_file(&count_file);
+        let Some(count) = count else {
+            result.failure = Some("counting child did not report a count".to_string());
+            return result;
+        };
+        result.expected_crash_count = count;
+
+        for n in 1..=count {
+            if let Some(f) = self.before.as_mut() {
+                f();
+            }
+            log_if_verbose(
+                Verbosity::Moderate,
+                format!("Running crash test child, will crash at failpoint {n}"),
+            );
+            let output = match self.run_child_process(n, None) {
+                Ok(output) => output,
+                Err(e) => {
+                    result.failure = Some(format!("failed to run child {n}: {e}"));
+                    return result;
+                }
+            };
+            if output.status.success() || output.status.code() == Some(TEST_FAILED) {
+                result.failure = Some(describe(
+                    &format!("child did not crash at failpoint {n}"),
+                    &output,
+                ));
+                return result;
+            }
+            result.crash_count += 1;
+
+            if let Err(e) = recover(n) {
+                result.failure = Some(format!(
+                    "recovery failed after a crash at failpoint {n}: {e:?}"
+                ));
+                return result;
+            }
+        }
+
+        result
+    }
+
+    #[cfg(not(feature = "failpoint_enabled"))]
+    pub fn run<B, R, E>(self, _body: B, _recover: R) -> CrashTestResult
+    where
+        B: FnOnce(),
+        R: FnMut(i64) -> Result<(), E>,
+        E: Debug,
+    {
+        CrashTestResult {
+            expected_crash_count: 0,
+            crash_count: 0,
+            failure: None,
+        }
+    }
+
+    #[cfg(feature = "failpoint_enabled")]
+    fn run_child_process(
+        &self,
+        trigger: i64,
+        count_file: Option<&PathBuf>,
+    ) -> std::io::Result<process::Output> {
+        let mut cmd = Command::new(std::env::current_exe()?);
+        cmd.args([
+            self.test_name.as_str(),
+            "--exact",
+            "--include-ignored",
+            "--test-threads=1",
+        ])
+        .env(TRIGGER_ENV, trigger.to_string());
+        if let Some(path) = count_file {
+            cmd.env(COUNT_FILE_ENV, path);
+        }
+        cmd.output()
+    }
+}
+
+// Runs `body` in a child process and exits.
+#[cfg(feature = "failpoint_enabled")]
+fn run_child<B: FnOnce()>(trigger: &str, body: B) -> ! {
+    let trigger = trigger.parse::<i64>().unwrap_or_else(|_| {
+        eprintln!("failpoint: bad {TRIGGER_ENV} \"{trigger}\"");
+        process::exit(TEST_FAILED)
+    });
+
+    if trigger == 0 {
+        start_counter();
+        body();
+        if let Some(path) = std::env::var_os(COUNT_FILE_ENV) {
+            if let Err(e) = std::fs::write(&path, get_count().to_string()) {
+                eprintln!("failpoint: can't write the count: {e}");
+                process::exit(TEST_FAILED);
+            }
+        }
+    } else {
+        lock_state().abort_on_trigger = true;
+        start_trigger(trigger);
+        body();
+    }
+    process::exit(0)
+}
+
+#[cfg(feature = "failpoint_enabled")]
+fn describe(what: &str, output: &process::Output) -> String {
+    format!(
+        "{what} ({}):\n{}{}",
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    )
+}
+
+/// The result of a [`CrashTest`].
+#[derive(Debug)]
+pub struct CrashTestResult {
+    /// The number of failpoints the counting child found.
+    pub expected_crash_count: i64,
+
+    /// The number of children that crashed and recovered.
+    pub crash_count: i64,
+
+    /// Why the test stopped early, if it did.
+    pub failure: Option<String>,
+}
+
+impl CrashTestResult {
+    pub fn success(&self) -> bool {
+        self.failure.is_none() && self.crash_count == self.expected_crash_count
+    }
+}
@@ -0,0 +1,60 @@
+/// Tests for action failpoints.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::cell::Cell;
+use std::io;
+
+use failpoint::{failpoint_action, fs, test_codepath};
+
+#[test]
+fn test_action_runs_only_when_triggered() {
+    let runs = Cell::new(0);
+
+    failpoint::start_counter();
+    failpoint_action!(|| runs.set(runs.get() + 1), "count me");
+    assert_eq!(runs.get(), 0);
+    assert_eq!(failpoint::get_count(), 1);
+
+    failpoint::start_trigger(2);
+    failpoint_action!(|| runs.set(runs.get() + 1));
+    assert_eq!(runs.get(), 0);
+    failpoint_action!(|| runs.set(runs.get() + 1));
+    assert_eq!(runs.get(), 1);
+
+    failpoint::start_counter();
+}
+
+// Reads a file that was checked to exist, but might be removed in
+// between.
+fn check_then_read(path: &std::path::Path) -> io::Result<String> {
+    if !path.exists() {
+        return Err(io::Error::from(io::ErrorKind::NotFound));
+    }
+    failpoint_action!(|| std::fs::remove_file(path).unwrap(), "remove file");
+    fs::read_to_string(path)
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_action_codepath() {
+    let path = std::env::temp_dir().join(format!("failpoint-action-{}", std::process::id()));
+
+    let res = test_codepath! {
+        before {
+            std::fs::write(&path, "data").unwrap();
+        };
+        codepath {
+            check_then_read(&path)
+        }
+    };
+
+    // The action, and the read, both make it fail.
+    assert!(res.success());
+    assert_eq!(res.expected_trigger_count, 2);
+    _ = std::fs::remove_file(&path);
+}
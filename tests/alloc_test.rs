@@ -0,0 +1,76 @@
+/// Tests for the failing allocator.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::alloc::System;
+use std::collections::TryReserveError;
+
+use failpoint::alloc::{AllocFailGuard, FailpointAllocator};
+use failpoint::test_codepath;
+
+#[global_allocator]
+static ALLOC: FailpointAllocator<System> = FailpointAllocator::new(System).with_min_size(1024);
+
+// Builds a table of two fallibly allocated buffers.
+fn make_table(len: usize) -> Result<Vec<Vec<u8>>, TryReserveError> {
+    let mut table = Vec::new();
+    for _ in 0..2 {
+        let mut buf = Vec::new();
+        buf.try_reserve_exact(len)?;
+        buf.resize(len, 0);
+        table.push(buf);
+    }
+    Ok(table)
+}
+
+#[test]
+fn test_alloc_fails_only_with_guard() {
+    failpoint::start_trigger(1);
+    // No guard, so no failpoint.
+    assert!(make_table(4096).is_ok());
+
+    failpoint::start_trigger(2);
+    let res = {
+        let _fail = AllocFailGuard::new();
+        make_table(4096)
+    };
+    assert!(res.is_err());
+
+    failpoint::start_counter();
+    let small = {
+        let _fail = AllocFailGuard::new();
+        make_table(16)
+    };
+    // Smaller than the minimum size.
+    assert!(small.is_ok());
+    assert_eq!(failpoint::get_count(), 0);
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_alloc_codepath() {
+    // Log everything, so the state allocates while it is locked.
+    failpoint::set_verbosity(failpoint::Verbosity::Extreme);
+    failpoint::set_logger(Some(Box::new(|msg| {
+        let _ = msg.repeat(100);
+    })));
+
+    let res = test_codepath! {
+        codepath {
+            {
+                let _fail = AllocFailGuard::new();
+                make_table(4096).map(|_| ())
+            }
+        }
+    };
+
+    failpoint::set_logger(None);
+    failpoint::set_verbosity(failpoint::Verbosity::None);
+
+    assert!(res.success());
+    assert_eq!(res.expected_trigger_count, 2);
+}
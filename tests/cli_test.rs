@@ -0,0 +1,59 @@
+#![cfg(all(feature = "cli", unix))]
+/// Tests for the `failpoint` command line tool.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test --features cli -- --test-threads=1
+/// ```
+use anyhow::Error;
+use std::process::Command;
+
+use failpoint::control::start_control_server;
+use failpoint::failpoint;
+
+fn code_under_test() -> Result<(), Error> {
+    let ret = Ok(());
+    let ret = failpoint!(ret, Error::msg("Error 1"), "first");
+    let ret = failpoint!(ret, Error::msg("Error 2"), "second");
+    ret
+}
+
+fn cli(socket: &std::path::Path, args: &[&str]) -> (bool, String) {
+    let out = Command::new(env!("CARGO_BIN_EXE_failpoint"))
+        .arg("--socket")
+        .arg(socket)
+        .args(args)
+        .output()
+        .unwrap();
+    (out.status.success(), String::from_utf8(out.stdout).unwrap())
+}
+
+#[test]
+fn test_cli_list_counter_and_trigger() {
+    let path = std::env::temp_dir().join(format!("failpoint-cli-{}.sock", std::process::id()));
+    let server = start_control_server(&path).unwrap();
+
+    assert!(cli(server.path(), &["verbosity", "extreme"]).0);
+    assert!(cli(server.path(), &["count"]).0);
+    assert!(code_under_test().is_ok());
+
+    let (ok, out) = cli(server.path(), &["counter"]);
+    assert!(ok);
+    assert_eq!(out.trim(), "2");
+
+    let (ok, out) = cli(server.path(), &["list"]);
+    assert!(ok);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("   1| Failpoint \"first\""));
+
+    assert!(cli(server.path(), &["trigger-named", "second"]).0);
+    assert_eq!(format!("{}", code_under_test().unwrap_err()), "Error 2");
+
+    assert!(!cli(server.path(), &["trigger", "nope"]).0);
+
+    failpoint::set_verbosity(failpoint::Verbosity::None);
+    failpoint::start_counter();
+}
@@ -0,0 +1,126 @@
+#![cfg(all(feature = "control", unix))]
+/// Tests for the control server.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test --features control -- --test-threads=1
+/// ```
+use anyhow::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use failpoint::control::{ControlClient, start_control_server};
+use failpoint::failpoint;
+
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("failpoint-{}-{}.sock", name, std::process::id()))
+}
+
+// Sends a command and returns the output lines and the final status
+// line.
+fn send(stream: &mut UnixStream, cmd: &str) -> (Vec<String>, String) {
+    writeln!(stream, "{cmd}").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end().to_string();
+        if line == "OK" || line.starts_with("ERR") {
+            return (lines, line);
+        }
+        lines.push(line);
+    }
+}
+
+fn code_under_test() -> Result<(), Error> {
+    let ret = Ok(());
+    let ret = failpoint!(ret, Error::msg("Error 1"), "first");
+    let ret = failpoint!(ret, Error::msg("Error 2"), "second");
+    ret
+}
+
+#[test]
+fn test_control_count_and_trigger() {
+    let server = start_control_server(socket_path("count")).unwrap();
+    let mut stream = UnixStream::connect(server.path()).unwrap();
+
+    assert_eq!(send(&mut stream, "verbosity extreme").1, "OK");
+    assert_eq!(send(&mut stream, "count").1, "OK");
+    assert!(code_under_test().is_ok());
+
+    let (status, ok) = send(&mut stream, "status");
+    assert_eq!(ok, "OK");
+    assert!(status.contains(&"mode count".to_string()));
+    assert!(status.contains(&"count 2".to_string()));
+
+    let (list, _) = send(&mut stream, "list");
+    assert_eq!(list.len(), 2);
+    assert!(list[1].starts_with("2 Failpoint \"second\""));
+
+    assert_eq!(send(&mut stream, "trigger 2").1, "OK");
+    assert_eq!(format!("{}", code_under_test().unwrap_err()), "Error 2");
+
+    assert_eq!(send(&mut stream, "trigger-named first").1, "OK");
+    assert_eq!(format!("{}", code_under_test().unwrap_err()), "Error 1");
+
+    failpoint::set_verbosity(failpoint::Verbosity::None);
+    failpoint::start_counter();
+}
+
+#[test]
+fn test_control_off() {
+    let server = start_control_server(socket_path("off")).unwrap();
+    let mut stream = UnixStream::connect(server.path()).unwrap();
+
+    assert_eq!(send(&mut stream, "trigger 1").1, "OK");
+    assert_eq!(send(&mut stream, "off").1, "OK");
+    assert!(code_under_test().is_ok());
+
+    assert_eq!(send(&mut stream, "on").1, "OK");
+    assert!(code_under_test().is_err());
+
+    failpoint::start_counter();
+}
+
+#[test]
+fn test_control_errors() {
+    let server = start_control_server(socket_path("errors")).unwrap();
+    let path = server.path().to_path_buf();
+    let mut stream = UnixStream::connect(&path).unwrap();
+
+    assert!(send(&mut stream, "bogus").1.starts_with("ERR"));
+    assert!(send(&mut stream, "trigger x").1.starts_with("ERR"));
+    assert!(send(&mut stream, "trigger 0").1.starts_with("ERR"));
+    assert!(send(&mut stream, "verbosity loud").1.starts_with("ERR"));
+
+    server.shutdown();
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_control_client_and_tail() {
+    let server = start_control_server(socket_path("tail")).unwrap();
+
+    let mut client = ControlClient::connect(server.path()).unwrap();
+    client.command("verbosity moderate").unwrap();
+    client.command("trigger-named second").unwrap();
+    assert!(client.command("bogus").is_err());
+
+    // The tap is registered before the server acknowledges the tail.
+    let mut tailer = UnixStream::connect(server.path()).unwrap();
+    assert_eq!(send(&mut tailer, "tail").1, "OK");
+
+    assert!(code_under_test().is_err());
+
+    let mut reader = BufReader::new(tailer);
+    let mut msg = String::new();
+    reader.read_line(&mut msg).unwrap();
+    assert!(msg.starts_with("Triggered Failpoint \"second\""));
+
+    client.command("verbosity none").unwrap();
+    failpoint::start_counter();
+}
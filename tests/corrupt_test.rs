@@ -0,0 +1,74 @@
+/// Tests for value-corruption failpoints.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use failpoint::{failpoint, failpoint_corrupt, test_codepath};
+
+// A record with a trailing checksum byte.
+fn encode(data: &[u8]) -> Vec<u8> {
+    let mut rec = data.to_vec();
+    rec.push(checksum(data));
+    rec
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |a, b| a.rotate_left(1) ^ b)
+}
+
+fn read_record(stored: &[u8]) -> Result<Vec<u8>, String> {
+    let res: Result<Vec<u8>, String> = Ok(stored.to_vec());
+    let res = failpoint!(res, "read failed".to_string(), "read");
+    let res = failpoint_corrupt!(res, |v| v[0] ^= 1, "flip a bit");
+    let mut rec = failpoint_corrupt!(res, |v| v.truncate(1), "truncate")?;
+
+    let sum = rec.pop().ok_or("empty record")?;
+    if checksum(&rec) != sum {
+        return Err("checksum mismatch".to_string());
+    }
+    Ok(rec)
+}
+
+#[test]
+fn test_corrupt_keeps_ok() {
+    let stored = encode(b"abc");
+
+    failpoint::start_trigger(2);
+    let res: Result<Vec<u8>, String> = Ok(stored.clone());
+    let res = failpoint_corrupt!(res, |v: &mut Vec<u8>| v.fill(0));
+    assert_eq!(res, Ok(stored.clone()));
+    let res: Result<Vec<u8>, String> = Ok(stored.clone());
+    let res = failpoint_corrupt!(res, |v: &mut Vec<u8>| v.fill(0));
+    assert_eq!(res, Ok(vec![0; 4]));
+
+    failpoint::start_counter();
+}
+
+#[test]
+fn test_corrupt_leaves_errors_alone() {
+    failpoint::start_trigger(1);
+    let res: Result<Vec<u8>, String> = Err("bad".to_string());
+    let res = failpoint_corrupt!(res, |v| v.clear(), "clear");
+    assert_eq!(res, Err("bad".to_string()));
+
+    failpoint::start_counter();
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_corrupt_codepath() {
+    let stored = encode(b"hello");
+
+    let res = test_codepath! {
+        codepath {
+            read_record(&stored)
+        }
+    };
+
+    // Every corruption is detected.
+    assert!(res.success());
+    assert_eq!(res.expected_trigger_count, 3);
+}
@@ -0,0 +1,120 @@
+/// Tests for crash points.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::cell::RefCell;
+
+use failpoint::{crashpoint, failpoint, test_codepath};
+
+// A store that writes a record, then a commit marker.  Only
+// committed records survive recovery.
+#[derive(Default)]
+struct Store {
+    records: Vec<String>,
+    committed: usize,
+}
+
+impl Store {
+    fn commit(&mut self, rec: &str) -> Result<(), String> {
+        let res: Result<(), String> = Ok(());
+        failpoint!(res, "write failed".to_string(), "write")?;
+        self.records.push(rec.to_string());
+        crashpoint!("after write");
+        self.committed = self.records.len();
+        crashpoint!("after commit");
+        Ok(())
+    }
+
+    fn recover(&mut self) {
+        self.records.truncate(self.committed);
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_crashpoint_recover() {
+    let store = RefCell::new(Store::default());
+    let recovered = RefCell::new(Vec::new());
+
+    let res = test_codepath! {
+        before {
+            *store.borrow_mut() = Store::default();
+        };
+        codepath {
+            store.borrow_mut().commit("a")
+        };
+        recover {
+            let mut s = store.borrow_mut();
+            s.recover();
+            recovered.borrow_mut().push(s.records.clone());
+        };
+        after {
+            // The store is consistent whether or not it crashed.
+            let s = store.borrow();
+            assert_eq!(s.records.len(), s.committed);
+        }
+    };
+
+    assert!(res.success());
+    assert_eq!(res.expected_trigger_count, 3);
+    // The write failure is not a crash, so recovery ran twice.
+    assert_eq!(*recovered.borrow(), vec![Vec::<String>::new(), vec!["a".to_string()]]);
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_crashpoint_without_recover() {
+    let res = test_codepath! {
+        codepath {
+            {
+                crashpoint!("only");
+                Ok::<(), String>(())
+            }
+        }
+    };
+
+    assert!(res.success());
+    assert_eq!(res.expected_trigger_count, 1);
+}
+
+#[rustfmt::skip]
+#[test]
+#[should_panic(expected = "a real panic")]
+fn test_crashpoint_other_panics_propagate() {
+    let _ = test_codepath! {
+        codepath {
+            {
+                crashpoint!("first");
+                if failpoint::get_count() == 1 {
+                    panic!("a real panic");
+                }
+                Ok::<(), String>(())
+            }
+        }
+    };
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_codepath_question_mark() {
+    fn step(n: i32) -> Result<i32, String> {
+        let res = Ok(n);
+        failpoint!(res, format!("step {n} failed"))
+    }
+
+    // The code path is run in a closure, so `?` makes the code path's
+    // result instead of returning from this function.
+    let res = test_codepath! {
+        codepath {
+            let a = step(1)?;
+            let b = step(2)?;
+            Ok::<i32, String>(a + b)
+        }
+    };
+    assert!(res.success());
+    assert_eq!(res.expected_trigger_count, 2);
+}
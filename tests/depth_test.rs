@@ -0,0 +1,107 @@
+/// Tests for exploring failpoints on error handling paths.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use failpoint::{Location, failpoint, test_codepath};
+
+fn step(desc: &'static str) -> Result<(), String> {
+    let res = Ok(());
+    failpoint!(res, format!("{desc} failed"), desc)
+}
+
+// Writes two records, rolling back both when either fails.  The
+// rollback has failpoints that are only reached after an error.
+fn save() -> Result<(), String> {
+    let res = step("write a").and_then(|_| step("write b"));
+    if res.is_err() {
+        step("rollback a")?;
+        step("rollback b")?;
+    }
+    res
+}
+
+// The ordinals of the errors injected in each failure sequence.
+fn ordinals(sequences: &[Vec<(i64, Location)>]) -> Vec<Vec<i64>> {
+    sequences
+        .iter()
+        .map(|s| s.iter().map(|(n, _)| *n).collect())
+        .collect()
+}
+
+// The descriptions of the failpoints that injected each failure
+// sequence's errors.
+fn descs(sequences: &[Vec<(i64, Location)>]) -> Vec<Vec<&str>> {
+    sequences
+        .iter()
+        .map(|s| s.iter().map(|(_, l)| l.desc.as_deref().unwrap()).collect())
+        .collect()
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_depth_one() {
+    let res = test_codepath! {
+        codepath {
+            save()
+        }
+    };
+
+    assert!(res.success());
+    assert_eq!(res.expected_trigger_count, 2);
+    assert_eq!(ordinals(&res.failure_sequences), vec![vec![1], vec![2]]);
+    assert_eq!(descs(&res.failure_sequences), vec![vec!["write a"], vec!["write b"]]);
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_depth_two() {
+    let res = test_codepath! {
+        depth 2;
+        codepath {
+            save()
+        }
+    };
+
+    // Failing "write a" reaches the two rollbacks at ordinals 2 and
+    // 3, and failing "write b" reaches them at 3 and 4.
+    assert!(res.success());
+    assert_eq!(res.expected_trigger_count, 6);
+    assert_eq!(res.trigger_count, 6);
+    assert_eq!(
+        ordinals(&res.failure_sequences),
+        vec![vec![1, 2], vec![1, 3], vec![2, 3], vec![2, 4]]
+    );
+    assert_eq!(
+        descs(&res.failure_sequences),
+        vec![
+            vec!["write a", "rollback a"],
+            vec!["write a", "rollback b"],
+            vec!["write b", "rollback a"],
+            vec!["write b", "rollback b"],
+        ]
+    );
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_depth_three() {
+    let res = test_codepath! {
+        depth 3;
+        before {};
+        codepath {
+            save()
+        }
+    };
+
+    // No failpoints are reached after a rollback fails, so there are
+    // no sequences of three.
+    assert!(res.success());
+    assert_eq!(
+        ordinals(&res.failure_sequences),
+        vec![vec![1, 2], vec![1, 3], vec![2, 3], vec![2, 4]]
+    );
+}
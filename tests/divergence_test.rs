@@ -0,0 +1,109 @@
+/// Tests for detecting code paths that aren't deterministic.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+
+use failpoint::{failpoint, test_codepath};
+
+fn step(desc: &'static str) -> Result<(), String> {
+    let res = Ok(());
+    failpoint!(res, format!("{desc} failed"), desc)
+}
+
+// Visits "a" and "b" in an order that changes on every run.
+fn unordered(runs: &AtomicU32) -> Result<(), String> {
+    if runs.fetch_add(1, Ordering::SeqCst).is_multiple_of(2) {
+        step("a")?;
+        step("b")
+    } else {
+        step("b")?;
+        step("a")
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_divergence_detected() {
+    let runs = AtomicU32::new(0);
+    let res = test_codepath! {
+        codepath {
+            unordered(&runs)
+        }
+    };
+
+    assert!(!res.success());
+    assert_eq!(res.trigger_count, 0);
+    let d = res.divergence.unwrap();
+    assert!(d.starts_with("path diverged at ordinal 1: expected Failpoint \"a\""), "{d}");
+    assert!(d.contains("got Failpoint \"b\""), "{d}");
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_diverged_run_is_cleaned_up() {
+    let runs = AtomicU32::new(0);
+    let open = AtomicI32::new(0);
+    let serial = test_codepath! {
+        before {
+            open.fetch_add(1, Ordering::SeqCst);
+        };
+        codepath {
+            unordered(&runs)
+        };
+        after {
+            open.fetch_sub(1, Ordering::SeqCst);
+        }
+    };
+    assert!(serial.divergence.is_some());
+    assert_eq!(open.load(Ordering::SeqCst), 0);
+
+    let runs = AtomicU32::new(0);
+    let parallel = test_codepath! {
+        threads 1;
+        before {
+            open.fetch_add(1, Ordering::SeqCst);
+        };
+        codepath {
+            unordered(&runs)
+        };
+        after {
+            open.fetch_sub(1, Ordering::SeqCst);
+        }
+    };
+    assert!(parallel.divergence.is_some());
+    assert_eq!(open.load(Ordering::SeqCst), 0);
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_deterministic_path() {
+    let res = test_codepath! {
+        codepath {
+            step("a").and_then(|_| step("b"))
+        }
+    };
+
+    assert!(res.success());
+    assert!(res.divergence.is_none());
+}
+
+#[test]
+fn test_longer_path_diverges() {
+    failpoint::start_counter();
+    _ = step("a");
+    failpoint::start_trigger(3);
+    _ = step("a");
+    _ = step("b");
+
+    let d = failpoint::get_divergence().unwrap();
+    assert!(
+        d.starts_with("path diverged at ordinal 2: expected the end of the path, got"),
+        "{d}"
+    );
+    failpoint::start_counter();
+}
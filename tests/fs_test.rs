@@ -0,0 +1,122 @@
+/// Tests for the filesystem facade.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use failpoint::io::{IoFault, TornWrite};
+use failpoint::{fs, test_codepath};
+
+// Makes an empty scratch directory for a test.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("failpoint-{name}-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Atomically replaces the contents of `path`.
+fn save(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut f = fs::File::create(&tmp)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+#[test]
+fn test_fs_trigger_named() {
+    let dir = scratch_dir("fs-named");
+    let path = dir.join("data");
+    fs::write(&path, "old").unwrap();
+
+    let desc = format!(
+        "fs::rename {} to {}",
+        path.with_extension("tmp").display(),
+        path.display()
+    );
+    failpoint::start_trigger_named(&desc);
+    let err = save(&path, b"new").unwrap_err();
+    assert!(err.to_string().contains("fs::rename"));
+    // The rename was not performed.
+    assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+    assert!(path.with_extension("tmp").exists());
+
+    failpoint::start_counter();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_fs_file_read() {
+    let dir = scratch_dir("fs-read");
+    let path = dir.join("data");
+    std::fs::write(&path, "hello").unwrap();
+
+    failpoint::start_counter();
+    let mut f = fs::File::open(&path).unwrap();
+    let mut s = String::new();
+    f.read_to_string(&mut s).unwrap();
+    assert_eq!(s, "hello");
+    assert_eq!(f.path(), path);
+    // open, then read twice.
+    assert_eq!(failpoint::get_count(), 3);
+
+    failpoint::start_trigger(1);
+    assert!(fs::File::open(&path).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_fs_codepath() {
+    let dir = scratch_dir("fs-codepath");
+    let path = dir.join("sub").join("data");
+
+    let res = test_codepath! {
+        before {
+            _ = std::fs::remove_dir_all(dir.join("sub"));
+        };
+        codepath {
+            {
+                fs::create_dir_all(path.parent().unwrap())
+                    .and_then(|_| save(&path, b"hello"))
+                    .and_then(|_| fs::read_to_string(&path))
+                    .and_then(|s| {
+                        assert_eq!(s, "hello");
+                        fs::remove_file(&path)
+                    })
+            }
+        }
+    };
+
+    // create_dir_all, create, write, sync_all, rename, read_to_string
+    // and remove_file.
+    assert!(res.success());
+    assert_eq!(res.expected_trigger_count, 7);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_fs_torn_write_truncates() {
+    let dir = scratch_dir("fs-torn");
+    let path = dir.join("wal");
+
+    let mut f = fs::File::create(&path)
+        .unwrap()
+        .with_fault(IoFault::Torn(TornWrite::Truncate(2)));
+
+    failpoint::start_trigger(2);
+    f.write_all(b"abcd").unwrap();
+    assert!(f.write_all(b"efgh").is_err());
+    drop(f);
+    assert_eq!(std::fs::read(&path).unwrap(), b"ab");
+
+    failpoint::start_counter();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,81 @@
+/// Tests for triggering failpoints by hit count.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use failpoint::failpoint;
+
+fn batch(n: i32) -> Vec<Result<i32, String>> {
+    let mut out = Vec::new();
+    for i in 1..=n {
+        let res: Result<i32, String> = Ok(i);
+        out.push(failpoint!(res, format!("item {i}"), "item"));
+        let res: Result<i32, String> = Ok(i);
+        _ = failpoint!(res, format!("other {i}"), "other");
+    }
+    out
+}
+
+#[test]
+fn test_trigger_hit() {
+    failpoint::start_trigger_hit("item", 3);
+    let res = batch(4);
+    assert!(res[0].is_ok() && res[1].is_ok() && res[3].is_ok());
+    assert_eq!(res[2], Err("item 3".to_string()));
+    failpoint::start_counter();
+}
+
+#[test]
+fn test_trigger_hit_range() {
+    failpoint::start_trigger_hits("item", 2..=4);
+    let res = batch(5);
+    let failed: Vec<bool> = res.iter().map(|r| r.is_err()).collect();
+    assert_eq!(failed, vec![false, true, true, true, false]);
+    failpoint::start_counter();
+}
+
+#[test]
+fn test_hit_counts() {
+    failpoint::start_counter();
+    batch(3);
+
+    let hits: Vec<(String, u64)> = failpoint::get_hit_counts()
+        .into_iter()
+        .map(|(loc, n)| (loc.desc.unwrap().into_owned(), n))
+        .collect();
+    assert_eq!(
+        hits,
+        vec![("item".to_string(), 3), ("other".to_string(), 3)]
+    );
+    assert_eq!(failpoint::get_count(), 6);
+
+    // The counts restart.
+    failpoint::start_counter();
+    assert!(failpoint::get_hit_counts().is_empty());
+}
+
+#[test]
+fn test_trigger_hit_through_wrapper() {
+    // Every path written by `fs::write` reaches the same failpoint
+    // location, so each description needs its own counter.
+    let dir = std::env::temp_dir().join(format!("failpoint-hits-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (a, b) = (dir.join("a"), dir.join("b"));
+
+    failpoint::start_trigger_hit(&format!("fs::write {}", a.display()), 2);
+    assert!(failpoint::fs::write(&a, "1").is_ok());
+    assert!(failpoint::fs::write(&b, "1").is_ok());
+    assert!(failpoint::fs::write(&a, "2").is_err());
+    assert!(failpoint::fs::write(&b, "2").is_ok());
+
+    let hits: Vec<u64> = failpoint::get_hit_counts()
+        .into_iter()
+        .map(|(_, n)| n)
+        .collect();
+    assert_eq!(hits, vec![2, 2]);
+    failpoint::start_counter();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
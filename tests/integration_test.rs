@@ -292,3 +292,62 @@ fn test_test_codepath_codepath_does_not_fail() {
     assert_eq!(1, res.expected_trigger_count);
     assert!(res.unexpected_result.is_some());
 }
+
+#[rustfmt::skip]
+#[test]
+fn test_event_logger() {
+    use failpoint::FailpointEvent;
+    use std::sync::{Arc, Mutex};
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events_clone = events.clone();
+
+    failpoint::set_verbosity(failpoint::Verbosity::Extreme);
+    failpoint::set_event_logger(Some(Box::new(move |e: &FailpointEvent| {
+	events_clone.lock().unwrap().push(e.clone());
+    })));
+
+    fn code_under_test() -> Result<(), Error> {
+        let ret = important_function();
+        let ret = failpoint!(ret, Error::msg("ERROR"), "Fail with \"ERROR\"");
+        ret
+    }
+
+    let res = test_codepath! {
+        codepath {
+            code_under_test()
+	}
+    };
+    assert!(res.success());
+
+    failpoint::set_verbosity(failpoint::Verbosity::None);
+    failpoint::set_event_logger(None);
+
+    let events = events.lock().unwrap();
+
+    let counted = events.iter().find_map(|e| match e {
+	FailpointEvent::Counted { loc, ordinal } => Some((loc.desc.clone(), *ordinal)),
+	_ => None,
+    });
+    assert_eq!(counted, Some((Some("Fail with \"ERROR\"".into()), 1)));
+
+    let triggered = events.iter().find_map(|e| match e {
+	FailpointEvent::Triggered { ordinal, error, .. } => Some((*ordinal, error.clone())),
+	_ => None,
+    });
+    let (ordinal, error) = triggered.unwrap();
+    assert_eq!(ordinal, 1);
+    assert!(error.starts_with("ERROR"));
+
+    let iterations: Vec<_> = events.iter().filter_map(|e| match e {
+	FailpointEvent::IterationStart { iteration, trigger } => Some((*iteration, *trigger)),
+	_ => None,
+    }).collect();
+    assert_eq!(iterations, vec![(0, 0), (1, 1)]);
+
+    assert!(matches!(
+	events.last(),
+	Some(FailpointEvent::Finished { counted: 1, triggered: 1 })
+    ));
+    assert_eq!(events.last().unwrap().to_string(), "Triggered 1 of 1 errors");
+}
@@ -0,0 +1,100 @@
+/// Tests for the interleaving explorer.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use failpoint::failpoint;
+use failpoint::interleave::Explorer;
+
+fn step(log: &Mutex<Vec<String>>, name: &str) -> Result<(), String> {
+    log.lock().unwrap().push(name.to_string());
+    let res: Result<(), String> = Ok(());
+    failpoint!(res, "error".to_string(), "step")
+}
+
+#[test]
+fn test_explores_all_schedules() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let s2 = seen.clone();
+    let res = Explorer::new().max_preemptions(usize::MAX).run(move |s| {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for t in ["a", "b"] {
+            let l = log.clone();
+            s.spawn(move || {
+                step(&l, &format!("{t}1")).unwrap();
+                step(&l, &format!("{t}2")).unwrap();
+            });
+        }
+        s.join();
+        s2.lock().unwrap().push(log.lock().unwrap().join(" "));
+    });
+
+    assert!(res.success());
+    let mut seen = seen.lock().unwrap().clone();
+    assert_eq!(seen.len(), res.schedule_count);
+    seen.sort();
+    seen.dedup();
+
+    // Every interleaving of two threads of two steps.  Some are run
+    // more than once, because switching threads after a thread's last
+    // step doesn't change the order of the steps.
+    assert_eq!(seen.len(), 6);
+    assert!(res.schedule_count > 6);
+}
+
+#[test]
+fn test_preemption_budget() {
+    let res = Explorer::new().max_preemptions(0).run(|s| {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for t in ["a", "b"] {
+            let l = log.clone();
+            s.spawn(move || {
+                step(&l, &format!("{t}1")).unwrap();
+                step(&l, &format!("{t}2")).unwrap();
+            });
+        }
+    });
+
+    // Without preemptions, only the choice of the first thread.
+    assert!(res.success());
+    assert_eq!(res.schedule_count, 2);
+}
+
+#[test]
+fn test_finds_lost_update() {
+    let res = Explorer::new().run(|s| {
+        let counter = Arc::new(AtomicI32::new(0));
+        for _ in 0..2 {
+            let c = counter.clone();
+            s.spawn(move || {
+                let n = c.load(Ordering::SeqCst);
+                let res: Result<(), String> = Ok(());
+                failpoint!(res, "error".to_string(), "increment").unwrap();
+                c.store(n + 1, Ordering::SeqCst);
+            });
+        }
+        s.join();
+        assert_eq!(counter.load(Ordering::SeqCst), 2, "lost update");
+    });
+
+    let failure = res.failure.unwrap();
+    assert_eq!(failure.schedule, vec![0, 1, 1]);
+    assert!(failure.message.contains("lost update"));
+}
+
+#[test]
+fn test_thread_panic_fails_schedule() {
+    let res = Explorer::new().run(|s| {
+        s.spawn(|| panic!("boom"));
+    });
+
+    let failure = res.failure.unwrap();
+    assert_eq!(failure.message, "thread 0 panicked: boom");
+}
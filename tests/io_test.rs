@@ -0,0 +1,105 @@
+/// Tests for the failing reader and writer adapters.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use failpoint::io::{FailRead, FailWrite, IoFault, TornWrite};
+use failpoint::test_codepath;
+
+#[test]
+fn test_fail_read_error_kind() {
+    let mut r = FailRead::new("hello".as_bytes()).with_error_kind(io::ErrorKind::UnexpectedEof);
+    let mut buf = [0u8; 5];
+
+    failpoint::start_counter();
+    assert_eq!(r.read(&mut buf).unwrap(), 5);
+    assert_eq!(failpoint::get_count(), 1);
+
+    failpoint::start_trigger(1);
+    let err = r.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert!(err.to_string().contains("\"FailRead::read\""));
+
+    failpoint::start_counter();
+}
+
+#[test]
+fn test_fail_read_short() {
+    let mut r = FailRead::new("hello!".as_bytes()).with_fault(IoFault::Short);
+    let mut buf = [0u8; 6];
+
+    failpoint::start_trigger(1);
+    assert_eq!(r.read(&mut buf).unwrap(), 3);
+    assert_eq!(&buf[..3], b"hel");
+    // Only the first read is short.
+    assert_eq!(r.read(&mut buf).unwrap(), 3);
+
+    failpoint::start_counter();
+}
+
+#[test]
+fn test_fail_write_and_seek() {
+    let mut w = FailWrite::new(Cursor::new(Vec::new())).with_fault(IoFault::Short);
+
+    failpoint::start_trigger(1);
+    assert_eq!(w.write(b"abcd").unwrap(), 2);
+    assert!(w.flush().is_ok());
+
+    failpoint::start_trigger(1);
+    assert!(w.seek(SeekFrom::Start(0)).is_err());
+    assert_eq!(w.get_ref().get_ref(), b"ab");
+
+    failpoint::start_counter();
+}
+
+#[test]
+fn test_fail_write_codepath() {
+    let mut out = Vec::new();
+
+    let res = test_codepath! {
+        before {
+            out.clear();
+        };
+        codepath {
+            {
+                let mut w = FailWrite::new(&mut out);
+                w.write_all(b"hello").and_then(|_| w.flush())
+            }
+        }
+    };
+
+    assert!(res.success());
+    assert_eq!(res.expected_trigger_count, 2);
+}
+
+#[test]
+fn test_fail_write_torn() {
+    let mut w = FailWrite::new(Vec::new()).with_fault(IoFault::Torn(TornWrite::Prefix(3)));
+
+    failpoint::start_trigger(2);
+    w.write_all(b"abcd").unwrap();
+    let err = w.write_all(b"efgh").unwrap_err();
+    assert!(err.to_string().contains("\"FailWrite::write\""));
+    assert_eq!(w.get_ref(), b"abcdefg");
+
+    // Random prefixes are shorter than the buffer, and are the same
+    // for the same seed.
+    let mut torn = Vec::new();
+    for _ in 0..2 {
+        let mut w = FailWrite::new(Vec::new()).with_fault(IoFault::Torn(TornWrite::RandomPrefix));
+        failpoint::set_seed(7);
+        failpoint::start_trigger(1);
+        assert!(w.write(b"0123456789").is_err());
+        assert!(w.get_ref().len() < 10);
+        torn.push(w.into_inner());
+    }
+    assert_eq!(torn[0], torn[1]);
+
+    failpoint::set_seed(0);
+    failpoint::start_counter();
+}
@@ -0,0 +1,132 @@
+/// Tests for limiting how much of a code path is explored.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::time::Duration;
+
+use failpoint::{Limits, failpoint, test_codepath};
+
+fn steps(n: i32) -> Result<(), String> {
+    for i in 0..n {
+        let res: Result<(), String> = Ok(());
+        failpoint!(res, format!("step {i} failed"), "step")?;
+    }
+    Ok(())
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_no_limits_is_complete() {
+    let res = test_codepath! {
+        codepath {
+            steps(5)
+        }
+    };
+
+    assert!(res.success());
+    assert!(res.complete);
+    assert!(res.skipped.is_empty());
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_max_iterations() {
+    let res = test_codepath! {
+        limits Limits::new().max_iterations(3);
+        codepath {
+            steps(5)
+        }
+    };
+
+    assert!(res.success());
+    assert!(!res.complete);
+    assert_eq!(res.trigger_count, 3);
+    assert_eq!(res.skipped, vec![vec![4], vec![5]]);
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_time_budget() {
+    let res = test_codepath! {
+        limits Limits::new().time_budget(Duration::ZERO);
+        codepath {
+            steps(5)
+        }
+    };
+
+    assert!(res.success());
+    assert!(!res.complete);
+    assert_eq!(res.trigger_count, 0);
+    assert_eq!(res.skipped.len(), 5);
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_sample() {
+    let run = |seed| test_codepath! {
+        limits Limits::new().sample(4, seed);
+        codepath {
+            steps(10)
+        }
+    };
+
+    let res = run(7);
+    assert!(res.success());
+    assert!(!res.complete);
+    assert_eq!(res.trigger_count, 4);
+    assert_eq!(res.skipped.len(), 6);
+
+    let mut all: Vec<i64> = res.failure_sequences.iter().map(|s| s[0].0).chain(res.skipped.iter().map(|s| s[0])).collect();
+    all.sort();
+    assert_eq!(all, (1..=10).collect::<Vec<i64>>());
+
+    // The same seed samples the same failpoints.
+    assert_eq!(run(7).failure_sequences, res.failure_sequences);
+}
+
+// Like `steps()`, but ignores the error injected at step `ignored`.
+fn steps_ignoring(n: i32, ignored: i32) -> Result<(), String> {
+    for i in 0..n {
+        let res: Result<(), String> = Ok(());
+        let res = failpoint!(res, format!("step {i} failed"), "step");
+        if i != ignored {
+            res?;
+        }
+    }
+    Ok(())
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_stopped_is_not_complete() {
+    let res = test_codepath! {
+        codepath {
+            steps_ignoring(5, 1)
+        }
+    };
+
+    assert!(!res.success());
+    assert!(res.unexpected_result.is_some());
+    assert!(!res.complete);
+    assert_eq!(res.trigger_count, 1);
+    assert_eq!(res.skipped, vec![vec![3], vec![4], vec![5]]);
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_stopped_in_parallel_is_not_complete() {
+    let res = test_codepath! {
+        threads 1;
+        codepath {
+            steps_ignoring(5, 1)
+        }
+    };
+
+    assert!(!res.success());
+    assert!(!res.complete);
+    assert_eq!(res.skipped, vec![vec![3], vec![4], vec![5]]);
+}
@@ -0,0 +1,84 @@
+#![cfg(feature = "log")]
+/// Tests for sending failpoint messages to the `log` crate.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test --features log -- --test-threads=1
+/// ```
+use anyhow::Error;
+use std::sync::Mutex;
+
+use failpoint::{failpoint, test_codepath};
+
+// A logger that records every message with the `failpoint` target.
+struct Recorder {
+    records: Mutex<Vec<(log::Level, String)>>,
+}
+
+impl log::Log for Recorder {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target() == "failpoint"
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            let mut records = self.records.lock().unwrap();
+            records.push((record.level(), format!("{}", record.args())));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static RECORDER: Recorder = Recorder {
+    records: Mutex::new(Vec::new()),
+};
+
+fn code_under_test() -> Result<(), Error> {
+    let ret = Ok(());
+    let ret = failpoint!(ret, Error::msg("Error 1"), "first");
+    ret
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_log_levels() {
+    log::set_logger(&RECORDER).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+
+    failpoint::set_logger(None);
+    failpoint::set_verbosity(failpoint::Verbosity::Moderate);
+
+    let res = test_codepath! {
+	codepath {
+	    code_under_test()
+	}
+    };
+    assert!(res.success());
+    res.report("test_log_levels");
+
+    // An unexpected failure, the failpoint is triggered on an error.
+    failpoint::start_trigger(1);
+    let ret: Result<(), Error> = Err(Error::msg("Already failed"));
+    _ = failpoint!(ret, Error::msg("Error 2"), "second");
+
+    failpoint::set_verbosity(failpoint::Verbosity::None);
+
+    let records = RECORDER.records.lock().unwrap();
+    let find = |prefix: &str| {
+        records
+            .iter()
+            .find(|(_, msg)| msg.starts_with(prefix))
+            .map(|(level, _)| *level)
+    };
+
+    assert_eq!(find("Found Failpoint \"first\""), Some(log::Level::Debug));
+    assert_eq!(find("Triggered Failpoint \"first\""), Some(log::Level::Debug));
+    assert_eq!(find("* Codepath:   test_log_levels"), Some(log::Level::Debug));
+    assert_eq!(find("* Result:     SUCCESS"), Some(log::Level::Debug));
+    assert_eq!(find("Unexpected error in Failpoint \"second\""), Some(log::Level::Warn));
+    // Only produced at `Verbosity::Extreme`.
+    assert_eq!(find("Testing codepath"), None);
+}
@@ -0,0 +1,120 @@
+/// Tests for minimizing failing schedules.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use failpoint::{Location, Schedule, failpoint};
+
+fn step(name: &'static str) -> Result<(), String> {
+    let res = Ok(());
+    failpoint!(res, format!("{name} failed"), name)
+}
+
+fn descs(locs: &[Location]) -> Vec<&str> {
+    locs.iter().map(|l| l.desc.as_deref().unwrap()).collect()
+}
+
+// Records a run that injects every failpoint it reaches.
+fn record_all(run: impl Fn() -> bool) -> Schedule {
+    failpoint::start_recording();
+    failpoint::start_trigger_sequence(&(1..=100).collect::<Vec<_>>());
+    assert!(run());
+    failpoint::stop_recording()
+}
+
+// Runs ten steps, and fails if "s3" and "s7" both failed.
+fn ten_steps() -> bool {
+    let names = ["s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9"];
+    let failed: Vec<&str> = names.into_iter().filter(|n| step(n).is_err()).collect();
+    failed.contains(&"s3") && failed.contains(&"s7")
+}
+
+#[test]
+fn test_minimize_to_pair() {
+    let s = record_all(ten_steps);
+    assert_eq!(s.injected_ordinals().len(), 10);
+    let locs = failpoint::minimize_schedule(&s, ten_steps).unwrap();
+    assert_eq!(descs(&locs), vec!["s3", "s7"]);
+}
+
+#[test]
+fn test_minimize_to_single() {
+    let run = || {
+        _ = step("a");
+        let b = step("b").is_err();
+        _ = step("c");
+        b
+    };
+    let s = record_all(run);
+    let locs = failpoint::minimize_schedule(&s, run).unwrap();
+    assert_eq!(descs(&locs), vec!["b"]);
+}
+
+#[test]
+fn test_minimize_counts_panics_as_failures() {
+    let run = || {
+        _ = step("a");
+        if step("b").is_err() {
+            panic!("b failed");
+        }
+        false
+    };
+    failpoint::start_recording();
+    failpoint::start_trigger_sequence(&[1, 2]);
+    assert!(std::panic::catch_unwind(run).is_err());
+    let s = failpoint::stop_recording();
+    let locs = failpoint::minimize_schedule(&s, run).unwrap();
+    assert_eq!(descs(&locs), vec!["b"]);
+}
+
+#[test]
+fn test_minimize_path_changes() {
+    // Injecting "a" skips "b", so "c" and "d" are recorded at ordinals
+    // 2 and 3.  Without "a" those ordinals are "b" and "c", but faults
+    // are injected by location, so "a" can still be removed.
+    let run = || {
+        if step("a").is_ok() {
+            _ = step("b");
+        }
+        let c = step("c").is_err();
+        let d = step("d").is_err();
+        c && d
+    };
+    let s = record_all(run);
+    let locs = failpoint::minimize_schedule(&s, run).unwrap();
+    assert_eq!(descs(&locs), vec!["c", "d"]);
+}
+
+#[test]
+fn test_minimize_does_not_fail() {
+    let s = record_all(ten_steps);
+    let err = failpoint::minimize_schedule(&s, || {
+        ten_steps();
+        false
+    })
+    .unwrap_err();
+    assert!(err.contains("does not fail"), "{err}");
+}
+
+#[test]
+fn test_minimize_schedule_does_not_match() {
+    let s = record_all(ten_steps);
+    let err = failpoint::minimize_schedule(&s, || step("other").is_err()).unwrap_err();
+    assert!(err.contains("does not match"), "{err}");
+}
+
+#[test]
+fn test_minimize_final_run_passes() {
+    // Only fails the first time each number of faults is injected, as
+    // flaky code might, so the last run of the minimal set passes.
+    let seen = std::cell::RefCell::new(std::collections::HashSet::new());
+    let s = record_all(ten_steps);
+    let err = failpoint::minimize_schedule(&s, || {
+        ten_steps() && seen.borrow_mut().insert(failpoint::get_injected_count())
+    })
+    .unwrap_err();
+    assert!(err.contains("minimal set"), "{err}");
+}
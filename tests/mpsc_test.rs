@@ -0,0 +1,79 @@
+/// Tests for the channel wrappers.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::sync::mpsc::{RecvTimeoutError, SendError, TryRecvError, TrySendError};
+use std::time::Duration;
+
+use failpoint::mpsc::{self, ChannelFault};
+use failpoint::test_codepath;
+
+#[test]
+fn test_channel_errors() {
+    let (tx, rx) = mpsc::channel();
+
+    failpoint::start_trigger(1);
+    assert_eq!(tx.send(1), Err(SendError(1)));
+    tx.send(2).unwrap();
+
+    failpoint::start_trigger(1);
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(2));
+
+    failpoint::start_trigger(1);
+    assert_eq!(
+        rx.recv_timeout(Duration::from_secs(5)),
+        Err(RecvTimeoutError::Timeout)
+    );
+
+    failpoint::start_counter();
+}
+
+#[test]
+fn test_sync_channel_disconnected() {
+    let (tx, rx) = mpsc::sync_channel(1);
+    let tx = tx.with_fault(ChannelFault::Disconnected);
+    let rx = rx.with_fault(ChannelFault::Disconnected);
+
+    failpoint::start_trigger(1);
+    assert_eq!(tx.try_send(1), Err(TrySendError::Disconnected(1)));
+    tx.try_send(2).unwrap();
+
+    failpoint::start_trigger(1);
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    assert_eq!(rx.try_recv(), Ok(2));
+
+    failpoint::start_counter();
+}
+
+// Sums the values sent, stopping at the first failure.
+fn send_and_sum(vals: &[i32]) -> Result<i32, String> {
+    let (tx, rx) = mpsc::sync_channel(vals.len());
+    for v in vals {
+        tx.send(*v).map_err(|e| e.to_string())?;
+    }
+    drop(tx);
+    let mut sum = 0;
+    for _ in vals {
+        sum += rx.recv().map_err(|e| e.to_string())?;
+    }
+    Ok(sum)
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_channel_codepath() {
+    let res = test_codepath! {
+        codepath {
+            send_and_sum(&[1, 2, 3])
+        }
+    };
+
+    // Three sends and three receives.
+    assert!(res.success());
+    assert_eq!(res.expected_trigger_count, 6);
+}
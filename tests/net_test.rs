@@ -0,0 +1,120 @@
+/// Tests for the network socket wrappers.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::io::{self, Read, Write};
+use std::thread;
+
+use failpoint::net::{TcpListener, TcpStream, UdpSocket};
+use failpoint::test_codepath;
+
+#[test]
+fn test_tcp_error_kinds() {
+    failpoint::start_counter();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    failpoint::start_trigger(1);
+    let err = TcpStream::connect(addr).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+    failpoint::start_counter();
+    let mut client = TcpStream::connect(addr).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    assert_eq!(failpoint::get_count(), 2);
+
+    failpoint::start_trigger(1);
+    let err = client.write(b"hi").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    client.write_all(b"hi").unwrap();
+
+    failpoint::start_trigger(1);
+    let mut buf = [0u8; 2];
+    let err = server.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hi");
+
+    failpoint::start_counter();
+}
+
+#[test]
+fn test_tcp_accepted_stream_inherits_fault() {
+    failpoint::start_counter();
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .with_error_kind(io::ErrorKind::TimedOut);
+    let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+
+    failpoint::start_trigger(1);
+    let mut buf = [0u8; 1];
+    assert_eq!(
+        server.read(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::TimedOut
+    );
+
+    drop(client);
+    failpoint::start_counter();
+}
+
+#[test]
+fn test_udp() {
+    failpoint::start_counter();
+    let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+    b.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+
+    failpoint::start_trigger(1);
+    let err = a.send_to(b"lost", b.local_addr().unwrap()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+    a.send_to(b"sent", b.local_addr().unwrap()).unwrap();
+    let mut buf = [0u8; 8];
+    let (n, from) = b.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"sent");
+    assert_eq!(from, a.local_addr().unwrap());
+
+    failpoint::start_counter();
+}
+
+// Sends a request and reads the echoed reply.
+fn echo(addr: std::net::SocketAddr, msg: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(msg)?;
+    stream.flush()?;
+    let mut reply = vec![0u8; msg.len()];
+    stream.read_exact(&mut reply)?;
+    Ok(reply)
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_tcp_codepath() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buf = [0u8; 5];
+            if stream.read_exact(&mut buf).is_ok() {
+                _ = stream.write_all(&buf);
+            }
+        }
+    });
+
+    let res = test_codepath! {
+        codepath {
+            echo(addr, b"hello")
+        }
+    };
+
+    // connect, write, flush and read.
+    assert!(res.success());
+    assert_eq!(res.expected_trigger_count, 4);
+}
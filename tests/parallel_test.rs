@@ -0,0 +1,119 @@
+/// Tests for running trigger iterations in parallel.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use failpoint::{Limits, Session, failpoint, test_codepath};
+
+fn step(desc: &'static str) -> Result<(), String> {
+    let res = Ok(());
+    failpoint!(res, format!("{desc} failed"), desc)
+}
+
+fn save() -> Result<(), String> {
+    let res = step("write a")
+        .and_then(|_| step("write b"))
+        .and_then(|_| step("write c"));
+    if res.is_err() {
+        step("rollback")?;
+    }
+    res
+}
+
+// Ignores a failure of "write b".
+fn sloppy_save() -> Result<(), String> {
+    step("write a")?;
+    _ = step("write b");
+    step("write c")
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_parallel_matches_serial() {
+    let serial = test_codepath! {
+        depth 2;
+        codepath {
+            save()
+        }
+    };
+
+    let runs = AtomicUsize::new(0);
+    let parallel = test_codepath! {
+        depth 2;
+        threads 4;
+        before {
+            runs.fetch_add(1, Ordering::SeqCst);
+        };
+        codepath {
+            save()
+        }
+    };
+
+    assert!(serial.success());
+    assert!(parallel.success());
+    assert_eq!(parallel.expected_trigger_count, serial.expected_trigger_count);
+    assert_eq!(parallel.trigger_count, serial.trigger_count);
+    assert_eq!(parallel.injected_count, serial.injected_count);
+    assert_eq!(parallel.failure_sequences, serial.failure_sequences);
+    assert_eq!(runs.load(Ordering::SeqCst) as i64, parallel.trigger_count + 1);
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_parallel_unexpected_result() {
+    let res = test_codepath! {
+        threads 2;
+        codepath {
+            sloppy_save()
+        }
+    };
+
+    assert!(!res.success());
+    assert_eq!(res.unexpected_result, Some(Ok(())));
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_parallel_limits() {
+    let res = test_codepath! {
+        limits Limits::new().max_iterations(2);
+        threads 3;
+        codepath {
+            save()
+        }
+    };
+
+    assert!(res.success());
+    assert!(!res.complete);
+    assert_eq!(res.trigger_count + res.skipped.len() as i64, 3);
+}
+
+#[test]
+fn test_session_is_isolated() {
+    failpoint::start_counter();
+    step("outer").unwrap();
+
+    {
+        let _session = Session::new();
+        assert_eq!(failpoint::get_count(), 0);
+        failpoint::start_trigger(1);
+        assert!(step("inner").is_err());
+    }
+
+    step("outer").unwrap();
+    assert_eq!(failpoint::get_count(), 2);
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "reverse order")]
+fn test_sessions_dropped_out_of_order() {
+    let outer = Session::new();
+    let _inner = Session::new();
+    drop(outer);
+}
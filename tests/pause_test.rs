@@ -0,0 +1,49 @@
+/// Tests for pause points.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use failpoint::{arm_pause, failpoint_pause, release, wait_until_paused};
+
+#[test]
+fn test_unarmed_pause_does_nothing() {
+    failpoint_pause!("not armed");
+    wait_until_paused("not armed");
+    release("not armed");
+}
+
+#[test]
+fn test_pause_interleaves_threads() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+
+    arm_pause("step");
+    let l = log.clone();
+    let t = thread::spawn(move || {
+        l.lock().unwrap().push("before");
+        failpoint_pause!("step");
+        l.lock().unwrap().push("after");
+    });
+
+    wait_until_paused("step");
+    log.lock().unwrap().push("main");
+    release("step");
+    t.join().unwrap();
+
+    assert_eq!(*log.lock().unwrap(), vec!["before", "main", "after"]);
+
+    // The pause point is disarmed once released.
+    failpoint_pause!("step");
+}
+
+#[test]
+fn test_release_before_pause_disarms() {
+    arm_pause("early");
+    release("early");
+    failpoint_pause!("early");
+}
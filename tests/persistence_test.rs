@@ -0,0 +1,100 @@
+/// Tests for failpoints that keep failing after they are triggered.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use failpoint::{Persistence, failpoint, test_codepath};
+
+// Connects, retrying up to `attempts` times.
+fn connect(attempts: u32) -> Result<u32, String> {
+    let mut last = Err("no attempts".to_string());
+    for a in 1..=attempts {
+        let res = Ok(a);
+        last = failpoint!(res, format!("attempt {a} refused"), "connect");
+        if last.is_ok() {
+            break;
+        }
+    }
+    last
+}
+
+fn setup() -> Result<(), String> {
+    let res = Ok(());
+    failpoint!(res, "setup failed".to_string(), "setup")
+}
+
+#[test]
+fn test_once_is_retried() {
+    failpoint::set_persistence(Persistence::Once);
+    failpoint::start_trigger(1);
+    assert_eq!(connect(3), Ok(2));
+    assert_eq!(failpoint::get_injected_count(), 1);
+}
+
+#[test]
+fn test_hits_then_recovers() {
+    failpoint::set_persistence(Persistence::Hits(1));
+    failpoint::start_trigger(1);
+    assert_eq!(connect(3), Ok(3));
+    assert_eq!(failpoint::get_injected_count(), 2);
+
+    failpoint::set_persistence(Persistence::Hits(2));
+    failpoint::start_trigger(1);
+    assert_eq!(connect(3), Err("attempt 3 refused".to_string()));
+    failpoint::set_persistence(Persistence::Once);
+}
+
+#[test]
+fn test_persistence_is_per_failpoint() {
+    failpoint::set_persistence(Persistence::Always);
+    failpoint::start_trigger(1);
+    assert!(setup().is_err());
+    assert_eq!(connect(2), Ok(1));
+    assert_eq!(failpoint::get_injected_count(), 1);
+    failpoint::set_persistence(Persistence::Once);
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_retries_give_up() {
+    failpoint::set_persistence(Persistence::Always);
+    let res = test_codepath! {
+        codepath {
+            connect(3)
+        }
+    };
+    failpoint::set_persistence(Persistence::Once);
+
+    assert!(res.success());
+    assert_eq!(res.expected_trigger_count, 1);
+    assert_eq!(res.injected_count, 3);
+}
+
+#[test]
+fn test_persistence_is_per_description() {
+    let dir = std::env::temp_dir().join(format!("failpoint-persist-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (a, b) = (dir.join("a"), dir.join("b"));
+
+    failpoint::set_persistence(Persistence::Always);
+    failpoint::start_trigger_named(&format!("fs::write {}", a.display()));
+    assert!(failpoint::fs::write(&a, "1").is_err());
+    assert!(failpoint::fs::write(&b, "1").is_ok());
+    assert!(failpoint::fs::write(&a, "2").is_err());
+    assert!(failpoint::fs::write(&b, "2").is_ok());
+    failpoint::set_persistence(Persistence::Once);
+    failpoint::start_counter();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_hit_trigger_persists() {
+    failpoint::set_persistence(Persistence::Hits(1));
+    failpoint::start_trigger_hit("connect", 1);
+    assert_eq!(connect(4), Ok(3));
+    assert_eq!(failpoint::get_injected_count(), 2);
+    failpoint::set_persistence(Persistence::Once);
+}
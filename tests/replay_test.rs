@@ -0,0 +1,154 @@
+/// Tests for recording and replaying failpoint decisions.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::io::Write;
+
+use failpoint::io::{FailWrite, IoFault, TornWrite};
+use failpoint::{Schedule, failpoint};
+
+fn step(name: &'static str) -> Result<(), String> {
+    let res = Ok(());
+    failpoint!(res, format!("{name} failed"), name)
+}
+
+// Runs "a", then "b", then "c" if "b" succeeded.
+fn run() -> Vec<bool> {
+    let a = step("a").is_err();
+    let b = step("b").is_err();
+    let mut out = vec![a, b];
+    if !b {
+        out.push(step("c").is_err());
+    }
+    out
+}
+
+fn record_trigger(n: i64) -> Schedule {
+    failpoint::start_recording();
+    failpoint::start_trigger(n);
+    run();
+    failpoint::stop_recording()
+}
+
+#[test]
+fn test_record() {
+    let s = record_trigger(1);
+    let names: Vec<_> = s
+        .decisions
+        .iter()
+        .map(|d| d.desc.clone().unwrap())
+        .collect();
+    assert_eq!(names, vec!["a", "b", "c"]);
+    assert_eq!(s.injected_ordinals(), vec![1]);
+    assert_eq!(s.decisions[2].ordinal, 3);
+}
+
+#[test]
+fn test_recording_restarts_with_run() {
+    failpoint::start_recording();
+    failpoint::start_trigger(1);
+    run();
+    failpoint::start_trigger(2);
+    step("a").unwrap();
+    let s = failpoint::stop_recording();
+    assert_eq!(s.decisions.len(), 1);
+    assert!(!s.decisions[0].injected);
+}
+
+#[test]
+fn test_replay() {
+    let s = record_trigger(2);
+    failpoint::start_counter();
+    failpoint::start_replay(&s);
+    assert_eq!(run(), vec![false, true]);
+    assert_eq!(failpoint::get_injected_count(), 1);
+    assert_eq!(failpoint::stop_replay(), Ok(()));
+}
+
+#[test]
+fn test_save_and_load() {
+    let mut s = record_trigger(3);
+    s.decisions[0].desc = Some("tab\there\nand \\ newline".to_string());
+    let path = std::env::temp_dir().join(format!("failpoint-replay-{}", std::process::id()));
+    s.save(&path).unwrap();
+    let loaded = Schedule::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded, s);
+}
+
+#[test]
+fn test_bad_schedule() {
+    let err = "1\tmaybe\tsrc/lib.rs\t3".parse::<Schedule>().unwrap_err();
+    assert!(err.contains("expected inject or pass"), "{err}");
+}
+
+#[test]
+fn test_replay_diverges() {
+    // Nothing was injected, so "b" and "c" were recorded too.
+    let s = record_trigger(4);
+    failpoint::start_replay(&s);
+    step("a").unwrap();
+    let err = failpoint::stop_replay().unwrap_err();
+    assert!(
+        err.starts_with("replay ended at ordinal 1: expected Failpoint \"b\""),
+        "{err}"
+    );
+}
+
+#[test]
+fn test_replay_wrong_location() {
+    let s = record_trigger(4);
+    failpoint::start_replay(&s);
+    assert!(step("b").is_ok());
+    let err = failpoint::stop_replay().unwrap_err();
+    assert!(
+        err.starts_with("replay diverged at ordinal 1: expected Failpoint \"a\""),
+        "{err}"
+    );
+
+    // Nothing is injected once the run has diverged.
+    failpoint::start_replay(&record_trigger(1));
+    assert!(step("b").is_ok());
+    assert!(step("a").is_ok());
+    assert!(failpoint::stop_replay().is_err());
+}
+
+#[test]
+fn test_replay_past_end() {
+    let s = record_trigger(1);
+    failpoint::start_replay(&s);
+    run();
+    assert!(step("d").is_ok());
+    let err = failpoint::stop_replay().unwrap_err();
+    assert!(err.contains("expected the end of the schedule"), "{err}");
+}
+
+// Writes 100 bytes through a writer that tears writes at random, and
+// returns how many were kept.
+fn torn_write() -> usize {
+    let mut w = FailWrite::new(Vec::new()).with_fault(IoFault::Torn(TornWrite::RandomPrefix));
+    assert!(w.write(&[0; 100]).is_err());
+    w.into_inner().len()
+}
+
+#[test]
+fn test_replay_restores_seed() {
+    failpoint::set_seed(7);
+    failpoint::start_recording();
+    failpoint::start_trigger(1);
+    let kept = torn_write();
+    let s = failpoint::stop_recording();
+    assert_eq!(s.seed, 7);
+    let s: Schedule = s.to_string().parse().unwrap();
+    assert_eq!(s.seed, 7);
+
+    failpoint::set_seed(12345);
+    failpoint::start_replay(&s);
+    assert_eq!(torn_write(), kept);
+    assert_eq!(failpoint::stop_replay(), Ok(()));
+    failpoint::set_seed(0);
+}
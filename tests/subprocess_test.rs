@@ -0,0 +1,73 @@
+/// Tests for crash testing in a subprocess.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test -- --test-threads=1
+/// ```
+use std::io::{self, Write};
+use std::path::Path;
+
+use failpoint::fs;
+use failpoint::subprocess::CrashTest;
+
+// Atomically replaces the contents of `path`.
+fn save(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut f = fs::File::create(&tmp)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+#[test]
+fn test_crash_subprocess() {
+    // Must be the same in the parent and the children.
+    let dir = std::env::temp_dir().join("failpoint-subprocess-test");
+    let path = dir.join("data");
+
+    let mut crashed_at = Vec::new();
+    let res = CrashTest::new("test_crash_subprocess")
+        .before(|| {
+            _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+        })
+        .run(
+            || save(&path, b"hello").unwrap(),
+            |n| {
+                crashed_at.push(n);
+                // The data is either all there or not there at all.
+                match std::fs::read(&path) {
+                    Ok(data) if data == b"hello" => Ok(()),
+                    Ok(data) => Err(format!("torn data {data:?}")),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    Err(e) => Err(e.to_string()),
+                }
+            },
+        );
+
+    assert!(res.success(), "{:?}", res.failure);
+    // create, write, sync_all and rename.
+    assert_eq!(res.expected_crash_count, 4);
+    assert_eq!(crashed_at, vec![1, 2, 3, 4]);
+
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_crash_subprocess_recovery_fails() {
+    let res = CrashTest::new("test_crash_subprocess_recovery_fails").run(
+        || {
+            failpoint::crashpoint!("only");
+        },
+        |_| Err("not recovered"),
+    );
+
+    assert!(!res.success());
+    assert_eq!(res.crash_count, 1);
+    assert_eq!(
+        res.failure.as_deref(),
+        Some("recovery failed after a crash at failpoint 1: \"not recovered\"")
+    );
+}
@@ -0,0 +1,140 @@
+#![cfg(feature = "tracing")]
+/// Tests for the `tracing` events and spans.
+///
+/// IMPORTANT: these tests must be run in a single thread, because
+/// they use a global shared state.  For example:
+///
+/// ```
+/// cargo test --features tracing -- --test-threads=1
+/// ```
+use anyhow::Error;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+use failpoint::{failpoint, test_codepath};
+
+// The fields of an event or span, formatted as strings.
+type Fields = HashMap<String, String>;
+
+#[derive(Default)]
+struct Recorded {
+    spans: Vec<Fields>,
+    // Each event, along with the id of the span it was in.
+    events: Vec<(Fields, Option<u64>)>,
+    current: Vec<u64>,
+}
+
+// A minimal subscriber that records the fields of each span and
+// event.
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Recorded>>);
+
+struct FieldVisitor<'a>(&'a mut Fields);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}"));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut fields = Fields::new();
+        span.record(&mut FieldVisitor(&mut fields));
+        let mut r = self.0.lock().unwrap();
+        r.spans.push(fields);
+        Id::from_u64(r.spans.len() as u64)
+    }
+
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::new();
+        event.record(&mut FieldVisitor(&mut fields));
+        let mut r = self.0.lock().unwrap();
+        let span = r.current.last().copied();
+        r.events.push((fields, span));
+    }
+
+    fn enter(&self, span: &Id) {
+        self.0.lock().unwrap().current.push(span.into_u64());
+    }
+
+    fn exit(&self, _: &Id) {
+        self.0.lock().unwrap().current.pop();
+    }
+}
+
+fn code_under_test() -> Result<(), Error> {
+    let ret = Ok(());
+    let ret = failpoint!(ret, Error::msg("Error 1"), "first");
+    let ret = failpoint!(ret, Error::msg("Error 2"));
+    ret
+}
+
+#[rustfmt::skip]
+#[test]
+fn test_tracing_events_and_spans() {
+    let recorder = Recorder::default();
+
+    let res = tracing::subscriber::with_default(recorder.clone(), || {
+	test_codepath! {
+	    codepath {
+		code_under_test()
+	    }
+	}
+    });
+    assert!(res.success());
+
+    let r = recorder.0.lock().unwrap();
+
+    // One span for counting, and one for each trigger.
+    assert_eq!(r.spans.len(), 3);
+    assert_eq!(r.spans[0]["codepath"], "code_under_test()");
+    assert_eq!(r.spans[0]["iteration"], "0");
+    assert_eq!(r.spans[0]["trigger"], "0");
+    assert_eq!(r.spans[2]["iteration"], "2");
+    assert_eq!(r.spans[2]["trigger"], "2");
+
+    let counted: Vec<_> = r
+        .events
+        .iter()
+        .filter(|(f, _)| f["message"] == "failpoint counted")
+        .collect();
+    assert_eq!(counted.len(), 2);
+    let (first, span) = counted[0];
+    assert_eq!(span, &Some(1));
+    assert_eq!(first["desc"], "first");
+    assert_eq!(first["ordinal"], "1");
+    assert_eq!(first["mode"], "count");
+    assert_eq!(first["crate"], "tracing_test");
+    assert!(first["file"].ends_with("tracing_test.rs"));
+    assert!(first.contains_key("line"));
+    // Failpoints without a description have no `desc` field.
+    assert!(!counted[1].0.contains_key("desc"));
+
+    let (second, span) = r
+        .events
+        .iter()
+        .find(|(f, _)| f["message"] == "failpoint triggered" && f["ordinal"] == "2")
+        .unwrap();
+    assert_eq!(span, &Some(3));
+    assert_eq!(second["mode"], "trigger");
+    assert!(second["error"].starts_with("Error 2"));
+}